@@ -0,0 +1,97 @@
+//! Access control of the peers which connect to a listener, by their ip address.
+
+pub use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Ranges of ip addresses which are allowed or denied to connect.
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    /// If not empty, only peers in one of these ranges are allowed.
+    pub allow: Vec<IpNet>,
+
+    /// Peers in any of these ranges are denied, even if they are in an allowed range.
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Whether a peer at `ip` may connect. Peers without an ip address, such as those connecting
+    /// over a unix socket, always may. IPv4 peers connecting to an IPv6 listener, whose address is
+    /// IPv4-mapped, are checked against the IPv4 ranges.
+    pub fn permits(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+            return true;
+        };
+
+        if self.deny.iter().any(|range| range.contains(&ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(allow: &[&str], deny: &[&str]) -> AccessList {
+        let parse = |ranges: &[&str]| ranges.iter().map(|range| range.parse().unwrap()).collect();
+        AccessList::new(parse(allow), parse(deny))
+    }
+
+    fn permits(list: &AccessList, ip: &str) -> bool {
+        list.permits(Some(ip.parse().unwrap()))
+    }
+
+    #[test]
+    fn empty_permits_everyone() {
+        let list = list(&[], &[]);
+
+        for ip in ["192.168.1.5", "10.0.0.1", "::1", "fe80::1"] {
+            assert!(permits(&list, ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn allow_permits_only_its_ranges() {
+        let list = list(&["192.168.1.0/24", "fd00::/8"], &[]);
+
+        assert!(permits(&list, "192.168.1.5"));
+        assert!(permits(&list, "fd12::1"));
+        assert!(!permits(&list, "192.168.2.5"));
+        assert!(!permits(&list, "fe80::1"));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let list = list(&["192.168.1.0/24"], &["192.168.1.128/25"]);
+
+        assert!(permits(&list, "192.168.1.5"));
+        assert!(!permits(&list, "192.168.1.200"));
+        assert!(!permits(&list, "10.0.0.1"));
+
+        // denying without allowing anything in particular permits everyone else
+        let list = self::list(&[], &["10.0.0.0/8"]);
+
+        assert!(!permits(&list, "10.1.2.3"));
+        assert!(permits(&list, "192.168.1.5"));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_are_checked_as_ipv4() {
+        let list = list(&["192.168.1.0/24"], &["192.168.1.200/32"]);
+
+        assert!(permits(&list, "::ffff:192.168.1.5"));
+        assert!(!permits(&list, "::ffff:192.168.1.200"));
+        assert!(!permits(&list, "::ffff:10.0.0.1"));
+    }
+
+    #[test]
+    fn peers_without_an_ip_are_permitted() {
+        assert!(list(&["192.168.1.0/24"], &["0.0.0.0/0", "::/0"]).permits(None));
+    }
+}
@@ -0,0 +1,451 @@
+//! Handling of the SET_ACTIVITY commands sent by apps, which is what most of them use Discord IPC
+//! for.
+//!
+//! Discord only accepts a few activity updates in a short time, yet some apps send one every frame
+//! or every second, often with the same activity every time. An [`ActivityFilter`] can forward at
+//! most one update per window, where updates which come in during the window are answered straight
+//! away and only the latest of them is forwarded once the window is over. It can also skip updates
+//! which are the same as the last one, which are answered straight away as well. An update which
+//! Discord answers with an error, such as when it is rate limited, isn't the last one anymore, so
+//! that the app can try it again.
+
+use crate::frame::opcode;
+use crate::Frame;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+/// The SET_ACTIVITY command carried by `frame`, if it carries one.
+pub fn set_activity(frame: &Frame) -> Option<Value> {
+    if frame.opcode != opcode::FRAME {
+        return None;
+    }
+
+    serde_json::from_slice::<Value>(&frame.payload)
+        .ok()
+        .filter(|command| command.get("cmd").is_some_and(|cmd| cmd == "SET_ACTIVITY"))
+}
+
+/// The response Discord answers a successful SET_ACTIVITY `command` with.
+pub fn success(command: &Value) -> Frame {
+    let payload = json!({
+        "cmd": "SET_ACTIVITY",
+        "evt": null,
+        "nonce": command.get("nonce").cloned().unwrap_or(Value::Null),
+        "data": command.pointer("/args/activity").cloned().unwrap_or(Value::Null),
+    });
+
+    Frame::new(opcode::FRAME, payload.to_string())
+}
+
+/// Timestamps below this are in seconds rather than milliseconds, which Discord accepts as well.
+const SECONDS_BELOW: i64 = 100_000_000_000;
+
+/// Shifts the start and end timestamps of the activity set by `frame` by `by` milliseconds, if it
+/// sets one which has any.
+pub fn shift_timestamps(frame: &mut Frame, by: i64) {
+    let Some(mut command) = set_activity(frame) else {
+        return;
+    };
+
+    let Some(timestamps) = command
+        .pointer_mut("/args/activity/timestamps")
+        .and_then(Value::as_object_mut)
+    else {
+        return;
+    };
+
+    for key in ["start", "end"] {
+        let Some(timestamp) = timestamps.get_mut(key) else {
+            continue;
+        };
+
+        if let Some(time) = timestamp.as_i64() {
+            let by = if time < SECONDS_BELOW { by / 1000 } else { by };
+            *timestamp = Value::from(time + by);
+        }
+    }
+
+    frame.payload = command.to_string().into_bytes();
+}
+
+/// `command` without its nonce, which is what tells two updates apart even if they are the same.
+fn without_nonce(command: &Value) -> Value {
+    let mut command = command.clone();
+
+    if let Some(command) = command.as_object_mut() {
+        command.remove("nonce");
+    }
+
+    command
+}
+
+/// An update which was forwarded.
+struct Forwarded {
+    nonce: Option<Value>,
+
+    /// The update without its nonce.
+    activity: Value,
+}
+
+/// Filters the activity updates of a single app, by coalescing and deduplicating them.
+pub struct ActivityFilter {
+    client_id: String,
+
+    /// Where the updates which aren't forwarded are answered.
+    reply_to: mpsc::Sender<Frame>,
+
+    /// How long to hold back updates after one was forwarded, if they are coalesced.
+    window: Option<Duration>,
+
+    /// Whether to skip updates which wouldn't change the activity.
+    dedup: bool,
+
+    /// When the last update was forwarded.
+    forwarded: Option<Instant>,
+
+    /// The last update which was forwarded, unless Discord failed to apply it.
+    last: Arc<Mutex<Option<Forwarded>>>,
+
+    /// The latest update which is yet to be forwarded, along with it without its nonce.
+    pending: Option<(Frame, Value)>,
+
+    /// How many updates were answered without being forwarded since the last one was.
+    coalesced: usize,
+
+    /// How many duplicate updates were skipped.
+    skipped: usize,
+}
+
+impl ActivityFilter {
+    pub fn new(client_id: String, reply_to: mpsc::Sender<Frame>) -> Self {
+        Self {
+            client_id,
+            reply_to,
+            window: None,
+            dedup: false,
+            forwarded: None,
+            last: Arc::default(),
+            pending: None,
+            coalesced: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Forwards at most one update every `window`.
+    pub fn coalesce(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Skips updates which are the same as the last one, apart from their nonce.
+    pub fn dedup(mut self) -> Self {
+        self.dedup = true;
+        self
+    }
+
+    /// Watches the answers to the updates this forwards.
+    pub fn answers(&self) -> Answers {
+        Answers {
+            last: Arc::clone(&self.last),
+        }
+    }
+
+    fn is_last(&self, activity: &Value) -> bool {
+        self.last
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|last| &last.activity == activity)
+    }
+
+    fn set_last(&self, command: &Value, activity: Value) {
+        *self.last.lock().unwrap() = Some(Forwarded {
+            nonce: command.get("nonce").cloned(),
+            activity,
+        });
+    }
+
+    async fn skip(&mut self, command: &Value) {
+        self.skipped += 1;
+        tracing::debug!(
+            "skipped a duplicate activity update of app {}, {} so far",
+            self.client_id,
+            self.skipped
+        );
+        let _ = self.reply_to.send(success(command)).await;
+    }
+
+    /// Returns `frame` if it is to be forwarded straight away. Otherwise it is either skipped, or
+    /// held until the window is over, replacing the update which was held before it. Either way,
+    /// the update which isn't forwarded is answered.
+    pub async fn offer(&mut self, frame: Frame) -> Option<Frame> {
+        let Some(command) = set_activity(&frame) else {
+            return Some(frame);
+        };
+        let activity = without_nonce(&command);
+
+        // what the activity ends up as if no other update comes in
+        let latest = self.pending.as_ref().map(|(_, pending)| pending);
+
+        let duplicate = match latest {
+            Some(latest) => latest == &activity,
+            None => self.is_last(&activity),
+        };
+
+        if self.dedup && duplicate {
+            self.skip(&command).await;
+            return None;
+        }
+
+        let Some(window) = self.window else {
+            self.set_last(&command, activity);
+            return Some(frame);
+        };
+
+        let now = Instant::now();
+        let window_over = self
+            .forwarded
+            .is_none_or(|forwarded| now >= forwarded + window);
+
+        if self.pending.is_none() && window_over {
+            self.forwarded = Some(now);
+            self.set_last(&command, activity);
+            return Some(frame);
+        }
+
+        if let Some((replaced, _)) = self.pending.replace((frame, activity)) {
+            let command = set_activity(&replaced).unwrap_or_default();
+            tracing::trace!("answering {replaced}, which was replaced by a later update");
+            self.coalesced += 1;
+            let _ = self.reply_to.send(success(&command)).await;
+        }
+
+        None
+    }
+
+    /// Waits until the window is over, then returns the update which was held during it. Never
+    /// returns if no update is held, or if the held update is skipped.
+    pub async fn due(&mut self) -> Frame {
+        let (Some(window), Some(forwarded), true) =
+            (self.window, self.forwarded, self.pending.is_some())
+        else {
+            return std::future::pending().await;
+        };
+
+        time::sleep_until(forwarded + window).await;
+
+        let (frame, activity) = self.pending.take().unwrap();
+        let command = set_activity(&frame).unwrap_or_default();
+        let coalesced = std::mem::take(&mut self.coalesced);
+
+        // the updates which were held back may have ended up where they started
+        if self.dedup && self.is_last(&activity) {
+            self.skip(&command).await;
+            return std::future::pending().await;
+        }
+
+        if coalesced > 0 {
+            tracing::debug!(
+                "forwarding the latest activity update of app {}, {coalesced} earlier one(s) were \
+                 coalesced",
+                self.client_id,
+            );
+        }
+
+        self.forwarded = Some(Instant::now());
+        self.set_last(&command, activity);
+        frame
+    }
+}
+
+impl Drop for ActivityFilter {
+    fn drop(&mut self) {
+        if self.skipped > 0 {
+            tracing::info!(
+                "skipped {} duplicate activity update(s) of app {}",
+                self.skipped,
+                self.client_id
+            );
+        }
+    }
+}
+
+/// Watches the answers Discord sends back for the updates an [`ActivityFilter`] forwarded.
+pub struct Answers {
+    last: Arc<Mutex<Option<Forwarded>>>,
+}
+
+impl Answers {
+    /// Forgets the last update which was forwarded if `frame` answers it with an error, so that
+    /// it isn't skipped when the app tries it again.
+    pub fn answered(&self, frame: &Frame) {
+        if frame.opcode != opcode::FRAME {
+            return;
+        }
+
+        let mut last = self.last.lock().unwrap();
+
+        let Some(nonce) = last.as_ref().and_then(|last| last.nonce.as_ref()) else {
+            return;
+        };
+
+        let Ok(answer) = serde_json::from_slice::<Value>(&frame.payload) else {
+            return;
+        };
+
+        let failed = answer.get("evt").is_some_and(|evt| evt == "ERROR")
+            && answer.get("nonce") == Some(nonce);
+
+        if failed {
+            tracing::debug!("Discord failed to set the last activity update: {frame}");
+            *last = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(4);
+
+    fn update(nonce: &str, state: &str) -> Frame {
+        let command = json!({
+            "cmd": "SET_ACTIVITY",
+            "nonce": nonce,
+            "args": { "pid": 1, "activity": { "state": state } },
+        });
+
+        Frame::new(opcode::FRAME, command.to_string())
+    }
+
+    fn error(nonce: &str) -> Frame {
+        let answer = json!({
+            "cmd": "SET_ACTIVITY",
+            "evt": "ERROR",
+            "nonce": nonce,
+            "data": { "code": 5000, "message": "rate limited" },
+        });
+
+        Frame::new(opcode::FRAME, answer.to_string())
+    }
+
+    /// The nonce of the update answered by `reply`.
+    fn answered(reply: Option<Frame>) -> Value {
+        let reply = reply.expect("the update was not answered");
+        serde_json::from_slice::<Value>(&reply.payload).unwrap()["nonce"].clone()
+    }
+
+    fn new_filter() -> (ActivityFilter, mpsc::Receiver<Frame>) {
+        let (sender, receiver) = mpsc::channel(16);
+        (ActivityFilter::new("123".to_owned(), sender), receiver)
+    }
+
+    #[tokio::test]
+    async fn forwards_everything_else() {
+        let (filter, _replies) = new_filter();
+        let mut filter = filter.coalesce(WINDOW).dedup();
+        let subscribe = Frame::new(opcode::FRAME, r#"{"cmd":"SUBSCRIBE","nonce":"1"}"#);
+        let handshake = Frame::new(opcode::HANDSHAKE, r#"{"v":1,"client_id":"123"}"#);
+
+        for _ in 0..2 {
+            assert_eq!(
+                filter.offer(subscribe.clone()).await,
+                Some(subscribe.clone())
+            );
+            assert_eq!(
+                filter.offer(handshake.clone()).await,
+                Some(handshake.clone())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn dedup_ignores_the_nonce() {
+        let (filter, mut replies) = new_filter();
+        let mut filter = filter.dedup();
+
+        assert_eq!(filter.offer(update("1", "a")).await, Some(update("1", "a")));
+        assert_eq!(filter.offer(update("2", "a")).await, None);
+        assert_eq!(answered(replies.try_recv().ok()), "2");
+
+        assert_eq!(filter.offer(update("3", "b")).await, Some(update("3", "b")));
+        assert_eq!(filter.offer(update("4", "a")).await, Some(update("4", "a")));
+        assert!(replies.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn dedup_forgets_an_update_answered_with_an_error() {
+        let (filter, mut replies) = new_filter();
+        let mut filter = filter.dedup();
+        let answers = filter.answers();
+
+        assert_eq!(filter.offer(update("1", "a")).await, Some(update("1", "a")));
+
+        // an error answering some other command doesn't count
+        answers.answered(&error("0"));
+        assert_eq!(filter.offer(update("2", "a")).await, None);
+        assert_eq!(answered(replies.try_recv().ok()), "2");
+
+        // neither does an answer which isn't an error
+        answers.answered(&update("1", "a"));
+        assert_eq!(filter.offer(update("3", "a")).await, None);
+        assert_eq!(answered(replies.try_recv().ok()), "3");
+
+        answers.answered(&error("1"));
+        assert_eq!(filter.offer(update("4", "a")).await, Some(update("4", "a")));
+        assert!(replies.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_within_the_window() {
+        let (filter, mut replies) = new_filter();
+        let mut filter = filter.coalesce(WINDOW);
+        let start = Instant::now();
+
+        assert_eq!(filter.offer(update("1", "a")).await, Some(update("1", "a")));
+
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(filter.offer(update("2", "b")).await, None);
+        assert!(replies.try_recv().is_err());
+
+        // only the latest update is forwarded, and the one it replaced is answered
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(filter.offer(update("3", "c")).await, None);
+        assert_eq!(answered(replies.try_recv().ok()), "2");
+
+        assert_eq!(filter.due().await, update("3", "c"));
+        assert_eq!(start.elapsed(), WINDOW);
+        assert!(replies.try_recv().is_err());
+
+        // the window starts over from when the held update was forwarded
+        time::advance(WINDOW - Duration::from_millis(1)).await;
+        assert_eq!(filter.offer(update("4", "d")).await, None);
+        assert_eq!(filter.due().await, update("4", "d"));
+        assert_eq!(start.elapsed(), WINDOW * 2);
+
+        time::advance(WINDOW).await;
+        assert_eq!(filter.offer(update("5", "e")).await, Some(update("5", "e")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalescing_back_to_the_last_update_is_skipped() {
+        let (filter, mut replies) = new_filter();
+        let mut filter = filter.coalesce(WINDOW).dedup();
+
+        assert_eq!(filter.offer(update("1", "a")).await, Some(update("1", "a")));
+        assert_eq!(filter.offer(update("2", "b")).await, None);
+        assert_eq!(filter.offer(update("3", "a")).await, None);
+        assert_eq!(answered(replies.try_recv().ok()), "2");
+
+        // the same as the held update rather than the last forwarded one
+        assert_eq!(filter.offer(update("4", "a")).await, None);
+        assert_eq!(answered(replies.try_recv().ok()), "4");
+
+        assert!(time::timeout(WINDOW * 2, filter.due()).await.is_err());
+        assert_eq!(answered(replies.try_recv().ok()), "3");
+    }
+}
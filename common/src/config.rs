@@ -0,0 +1,618 @@
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, Parser};
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::value::{Dict, Map, Value};
+use figment::{Figment, Metadata, Profile, Provider, Source};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::{env, iter};
+
+/// The prefix of environment variables which can override values in the configuration file.
+pub const ENV_PREFIX: &str = "DIP_";
+
+/// The environment variable which overrides the location of the configuration file.
+pub const CONFIG_ENV: &str = "DIP_CONFIG";
+
+/// The environment variable which selects a profile if `--profile` isn't passed.
+pub const PROFILE_ENV: &str = "DIP_PROFILE";
+
+/// The name of the configuration file shared by the host and the remote, which has their
+/// configurations in the `[host]` and `[remote]` tables respectively.
+pub const SHARED_FILE_NAME: &str = "dip.toml";
+
+const SECTIONS: [&str; 2] = ["host", "remote"];
+
+/// Arguments shared by the host and the remote which affect how the configuration is read, rather
+/// than being part of the configuration itself.
+#[derive(Args, Default, Clone)]
+pub struct CommonArgs {
+    /// The configuration file to read. Overrides the `DIP_CONFIG` environment variable and the
+    /// default locations.
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Print the resolved configuration along with where each value came from, then exit.
+    #[clap(long)]
+    pub print_config: bool,
+
+    /// The named profile to apply on top of the configuration file, from its `[profiles.<name>]`
+    /// table (or `[host.profiles.<name>]` and `[remote.profiles.<name>]` in `dip.toml`). Overrides
+    /// the `DIP_PROFILE` environment variable.
+    #[clap(long)]
+    pub profile: Option<String>,
+}
+
+impl CommonArgs {
+    pub fn profile(&self) -> Option<String> {
+        self.profile.clone().or_else(|| {
+            env::var(PROFILE_ENV)
+                .ok()
+                .filter(|profile| !profile.is_empty())
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFileSource {
+    Argument,
+    Environment,
+    User,
+    System,
+}
+
+impl fmt::Display for ConfigFileSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Argument => f.write_str("--config argument"),
+            Self::Environment => write!(f, "{CONFIG_ENV} environment variable"),
+            Self::User => f.write_str("user configuration directory"),
+            Self::System => f.write_str("system configuration directory"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub source: ConfigFileSource,
+}
+
+impl ConfigFile {
+    /// Finds the configuration file named `file_name`, or the shared `dip.toml`. An explicitly
+    /// given path is used as is, otherwise the first file which exists in the user configuration
+    /// directory, then `$XDG_CONFIG_DIRS/dip` and `/etc/dip` is used, preferring `file_name` over
+    /// `dip.toml` in the same directory. If none of them exist, the (nonexistent) `file_name` in
+    /// the user configuration directory is used.
+    pub fn locate(file_name: &str, args: &CommonArgs) -> anyhow::Result<Self> {
+        let explicit = args
+            .config
+            .clone()
+            .map(|path| (path, ConfigFileSource::Argument))
+            .or_else(|| {
+                env::var_os(CONFIG_ENV)
+                    .filter(|path| !path.is_empty())
+                    .map(|path| (PathBuf::from(path), ConfigFileSource::Environment))
+            });
+
+        if let Some((path, source)) = explicit {
+            anyhow::ensure!(
+                path.is_file(),
+                "config file {} (from {source}) does not exist",
+                path.display()
+            );
+
+            return Ok(Self { path, source });
+        }
+
+        let user_dir = crate::dirs().config_dir();
+        let user = Self {
+            path: user_dir.join(file_name),
+            source: ConfigFileSource::User,
+        };
+        let dirs = iter::once((user_dir.to_owned(), ConfigFileSource::User)).chain(
+            system_config_dirs()
+                .into_iter()
+                .map(|dir| (dir, ConfigFileSource::System)),
+        );
+
+        Ok(dirs
+            .flat_map(|(dir, source)| {
+                [file_name, SHARED_FILE_NAME].map(|file_name| Self {
+                    path: dir.join(file_name),
+                    source,
+                })
+            })
+            .find(|file| file.path.is_file())
+            .unwrap_or(user))
+    }
+}
+
+#[cfg(unix)]
+fn system_config_dirs() -> Vec<PathBuf> {
+    let xdg_config_dirs = env::var_os("XDG_CONFIG_DIRS")
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/etc/xdg".into());
+
+    env::split_paths(&xdg_config_dirs)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("dip"))
+        .chain(iter::once(PathBuf::from("/etc/dip")))
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn system_config_dirs() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+pub trait ConfigLike<'de>: Serialize + Deserialize<'de> + Parser + Default + Sized {
+    const FILE_NAME: &'static str;
+
+    /// The table this configuration lives in within the shared `dip.toml`.
+    const SECTION: &'static str;
+
+    /// The keys which take effect without a restart when the configuration is reloaded.
+    const RELOADABLE: &'static [&'static str];
+
+    fn discord_ipc_path(&self) -> &Option<PathBuf>;
+    fn common_args(&self) -> &CommonArgs;
+    fn log(&self) -> Option<&str>;
+    fn watch_config(&self) -> bool;
+
+    /// Checks the configuration for errors which can't be expressed through its types.
+    fn validate(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Parses the command line arguments and locates the configuration file.
+    fn loader() -> anyhow::Result<Loader<Self>> {
+        Self::loader_from(env::args_os())
+    }
+
+    /// Like [`loader`](Self::loader), but parses the given command line arguments.
+    fn loader_from(
+        args: impl IntoIterator<Item = impl Into<OsString> + Clone>,
+    ) -> anyhow::Result<Loader<Self>> {
+        let matches = Self::command().get_matches_from(args);
+        let arguments = Self::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+        let args = arguments.common_args().clone();
+        let file = ConfigFile::locate(Self::FILE_NAME, &args)?;
+
+        Ok(Loader {
+            arguments: Arguments::new(arguments, &matches),
+            profile: args.profile(),
+            args,
+            file,
+        })
+    }
+
+    fn socket_path(
+        &self,
+        find_socket_fn: impl FnOnce() -> Option<PathBuf>,
+        on_error: &'static str,
+    ) -> anyhow::Result<Cow<'_, Path>> {
+        self.discord_ipc_path()
+            .as_deref()
+            .map(Cow::Borrowed)
+            .or_else(|| Some(Cow::Owned(find_socket_fn()?)))
+            .context(on_error)
+    }
+}
+
+/// Reads the configuration, with values resolved in the following order (highest priority first):
+/// arguments which were passed on the command line, `DIP_*` environment variables, the
+/// configuration file, then the built-in defaults.
+pub struct Loader<C> {
+    arguments: Arguments<C>,
+    args: CommonArgs,
+    file: ConfigFile,
+    profile: Option<String>,
+}
+
+impl<'de, C: ConfigLike<'de>> Loader<C> {
+    pub fn args(&self) -> &CommonArgs {
+        &self.args
+    }
+
+    /// The command line arguments as they were parsed, including flags which are only meaningful
+    /// on the command line and are skipped by the configuration.
+    pub fn arguments(&self) -> &C {
+        &self.arguments.arguments
+    }
+
+    pub fn file(&self) -> &ConfigFile {
+        &self.file
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Returns the configuration file, focused on this configuration's table if it is a shared
+    /// `dip.toml`, and the selected profile within it.
+    fn file_layers(&self) -> anyhow::Result<(Figment, Option<Figment>)> {
+        // the profile is read from its own provider so that its values can be told apart from the
+        // values of the rest of the file
+        let read = || {
+            let file = Figment::from(Toml::file(&self.file.path));
+            let is_shared = SECTIONS
+                .iter()
+                .any(|section| matches!(file.find_value(section), Ok(Value::Dict(..))));
+
+            if is_shared {
+                file.focus(C::SECTION)
+            } else {
+                file
+            }
+        };
+
+        let profile = match &self.profile {
+            Some(profile) => {
+                let key = format!("profiles.{profile}");
+                let file = read();
+                anyhow::ensure!(
+                    file.contains(&key),
+                    "profile `{profile}` does not exist in {}",
+                    self.file.path.display()
+                );
+
+                Some(file.focus(&key))
+            }
+            None => None,
+        };
+
+        Ok((read(), profile))
+    }
+
+    fn layer(&self, file: Figment, profile: Option<Figment>) -> Figment {
+        let mut figment = Figment::new().merge(Defaults::<C>::default()).merge(file);
+
+        if let Some(profile) = profile {
+            figment = figment.merge(profile);
+        }
+
+        figment.merge(environment()).merge(&self.arguments)
+    }
+
+    pub fn figment(&self) -> anyhow::Result<Figment> {
+        let (file, profile) = self.file_layers()?;
+        Ok(self.layer(file, profile))
+    }
+
+    pub fn load(&self) -> anyhow::Result<C> {
+        extract(&self.figment()?)
+    }
+
+    /// Renders the resolved configuration as TOML, with each key annotated with the source its
+    /// value came from.
+    pub fn show(&self) -> anyhow::Result<String> {
+        let (file, profile) = self.file_layers()?;
+        let figment = self.layer(file, profile.clone());
+        let config: C = extract(&figment)?;
+        let values = Value::serialize(&config)
+            .context("failed to serialize config")?
+            .into_dict()
+            .context("config is not a table")?;
+
+        let mut output = format!(
+            "# config file: {} (from {}{})\n",
+            self.file.path.display(),
+            self.file.source,
+            if self.file.path.is_file() {
+                ""
+            } else {
+                ", does not exist"
+            }
+        );
+
+        if let Some(profile) = &self.profile {
+            output.push_str(&format!("# profile: {profile}\n"));
+        }
+
+        output.push('\n');
+
+        // tables must come after every other value, otherwise the values following a table would
+        // be parsed as part of that table
+        let (values, tables): (Vec<_>, Vec<_>) =
+            values.into_iter().partition(|(_, value)| !is_table(value));
+
+        for (key, value) in values.into_iter().chain(tables) {
+            let mut provenance = figment
+                .find_metadata(&key)
+                .map(|metadata| provenance(metadata, &key))
+                .unwrap_or_else(|| "unknown source".to_owned());
+
+            if let (Some(name), Some(profile)) = (&self.profile, &profile) {
+                let tag =
+                    |figment: &Figment| figment.find_value(&key).ok().map(|value| value.tag());
+
+                if tag(profile).is_some() && tag(profile) == tag(&figment) {
+                    provenance.push_str(&format!(" (profile {name})"));
+                }
+            }
+
+            output.push_str(&format!("# from {provenance}\n"));
+
+            if let Value::Empty(..) = value {
+                output.push_str(&format!("# {key} is not set\n\n"));
+            } else {
+                let value = toml::to_string(&Dict::from([(key, value)]))
+                    .context("failed to serialize config as toml")?;
+                output.push_str(&value);
+                output.push('\n');
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+fn environment() -> Env {
+    Env::prefixed(ENV_PREFIX).ignore(&["config", "profile"])
+}
+
+fn is_table(value: &Value) -> bool {
+    match value {
+        Value::Dict(..) => true,
+        Value::Array(_, values) => values.first().is_some_and(is_table),
+        _ => false,
+    }
+}
+
+fn provenance(metadata: &Metadata, key: &str) -> String {
+    if let Some(Source::File(path)) = &metadata.source {
+        format!("{}", path.display())
+    } else if *metadata == environment().metadata() {
+        let variable = metadata.interpolate(&Profile::Default, &[key]);
+        format!("environment variable {ENV_PREFIX}{variable}")
+    } else {
+        metadata.name.to_string()
+    }
+}
+
+/// Extracts the configuration from `figment`. If that fails, every key is extracted on its own, with
+/// the others set to their defaults, so that every invalid key is listed instead of only the first
+/// one.
+fn extract<'de, C: ConfigLike<'de>>(figment: &Figment) -> anyhow::Result<C> {
+    let error = match figment.extract() {
+        Ok(config) => return Ok(config),
+        Err(error) => error,
+    };
+
+    let keys = figment.extract::<Dict>().unwrap_or_default();
+    let defaults = Value::serialize(C::default())
+        .ok()
+        .and_then(Value::into_dict)
+        .unwrap_or_default();
+
+    let mut errors = keys
+        .into_keys()
+        .filter_map(|key| {
+            let mut others = defaults.clone();
+            others.remove(&key);
+
+            let error = figment
+                .clone()
+                .merge(Serialized::defaults(others))
+                .extract::<C>()
+                .err()?;
+
+            // errors in flattened tables don't know which key they are for
+            Some(error.into_iter().map(move |mut error| {
+                if error.path.is_empty() {
+                    error.metadata = figment.find_metadata(&key).cloned();
+                    error.path.push(key.clone());
+                }
+
+                error
+            }))
+        })
+        .flatten()
+        .map(|error| format!("\n  - {error}"))
+        .collect::<String>();
+
+    // the error may not be down to any key on its own
+    if errors.is_empty() {
+        errors = error
+            .into_iter()
+            .map(|error| format!("\n  - {error}"))
+            .collect();
+    }
+
+    anyhow::bail!("invalid config:{errors}")
+}
+
+/// A [`Provider`] for the built-in defaults of a configuration.
+pub struct Defaults<C>(PhantomData<C>);
+
+impl<C> Default for Defaults<C> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<C: Serialize + Default> Provider for Defaults<C> {
+    fn metadata(&self) -> Metadata {
+        Metadata::named("built-in defaults")
+    }
+
+    fn data(&self) -> figment::Result<Map<Profile, Dict>> {
+        Serialized::defaults(C::default()).data()
+    }
+}
+
+/// A [`Provider`] which only provides the arguments that were explicitly passed on the command
+/// line, so that arguments which weren't passed don't shadow values from other sources.
+pub struct Arguments<T> {
+    arguments: T,
+    passed: Vec<String>,
+}
+
+impl<T: Serialize> Arguments<T> {
+    pub fn new(arguments: T, matches: &ArgMatches) -> Self {
+        let passed = matches
+            .ids()
+            .map(|id| id.as_str())
+            .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+            .map(ToOwned::to_owned)
+            .collect();
+
+        Self { arguments, passed }
+    }
+}
+
+impl<T: Serialize> Provider for Arguments<T> {
+    fn metadata(&self) -> Metadata {
+        Metadata::named("command line arguments")
+    }
+
+    fn data(&self) -> figment::Result<Map<Profile, Dict>> {
+        let mut data = Serialized::defaults(&self.arguments).data()?;
+
+        for dict in data.values_mut() {
+            dict.retain(|key, _| self.passed.contains(key));
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+// `Jail` closures must return figment's error, which is large
+#[allow(clippy::result_large_err)]
+mod tests {
+    use super::*;
+    use figment::Jail;
+
+    #[derive(Serialize, Deserialize, Parser)]
+    struct TestConfig {
+        #[clap(long)]
+        default: Option<String>,
+        #[clap(long)]
+        file: Option<String>,
+        #[clap(long)]
+        profiled: Option<String>,
+        #[clap(long)]
+        environment: Option<String>,
+        #[clap(long)]
+        argument: Option<String>,
+
+        #[clap(skip)]
+        #[serde(skip)]
+        discord_ipc_path: Option<PathBuf>,
+
+        #[clap(flatten)]
+        #[serde(skip)]
+        common: CommonArgs,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> Self {
+            let default = || Some("default".to_owned());
+
+            Self {
+                default: default(),
+                file: default(),
+                profiled: default(),
+                environment: default(),
+                argument: default(),
+                discord_ipc_path: None,
+                common: CommonArgs::default(),
+            }
+        }
+    }
+
+    impl ConfigLike<'_> for TestConfig {
+        const FILE_NAME: &'static str = "test.toml";
+        const SECTION: &'static str = "host";
+        const RELOADABLE: &'static [&'static str] = &[];
+
+        fn discord_ipc_path(&self) -> &Option<PathBuf> {
+            &self.discord_ipc_path
+        }
+
+        fn common_args(&self) -> &CommonArgs {
+            &self.common
+        }
+
+        fn log(&self) -> Option<&str> {
+            None
+        }
+
+        fn watch_config(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn resolves_each_key_to_its_highest_layer() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "test.toml",
+                r#"
+                    file = "file"
+                    profiled = "file"
+                    environment = "file"
+                    argument = "file"
+
+                    [profiles.test]
+                    profiled = "profile"
+                    environment = "profile"
+                    argument = "profile"
+                "#,
+            )?;
+            jail.set_env("DIP_ENVIRONMENT", "environment");
+            jail.set_env("DIP_ARGUMENT", "environment");
+
+            let loader = TestConfig::loader_from([
+                "test",
+                "--config",
+                "test.toml",
+                "--profile",
+                "test",
+                "--argument",
+                "argument",
+            ])
+            .map_err(|error| error.to_string())?;
+            let config = loader.load().map_err(|error| error.to_string())?;
+
+            assert_eq!(config.default.as_deref(), Some("default"));
+            assert_eq!(config.file.as_deref(), Some("file"));
+            assert_eq!(config.profiled.as_deref(), Some("profile"));
+            assert_eq!(config.environment.as_deref(), Some("environment"));
+            assert_eq!(config.argument.as_deref(), Some("argument"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn reads_the_section_of_a_shared_file() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "dip.toml",
+                r#"
+                    [host]
+                    file = "host"
+
+                    [remote]
+                    file = "remote"
+                "#,
+            )?;
+
+            let loader = TestConfig::loader_from(["test", "--config", "dip.toml"])
+                .map_err(|error| error.to_string())?;
+            let config = loader.load().map_err(|error| error.to_string())?;
+
+            assert_eq!(config.file.as_deref(), Some("host"));
+            assert_eq!(config.argument.as_deref(), Some("default"));
+
+            Ok(())
+        });
+    }
+}
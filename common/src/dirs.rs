@@ -4,22 +4,28 @@ use std::sync::OnceLock;
 
 static DIRS: OnceLock<ProjectDirs> = OnceLock::new();
 
+/// Locates the project directories. Does nothing if they were already located.
 pub fn initialize() -> anyhow::Result<()> {
+    if DIRS.get().is_some() {
+        return Ok(());
+    }
+
     #[cfg(any(not(windows), not(target_os = "macos")))]
     let application = "dip";
 
     #[cfg(any(windows, target_os = "macos"))]
     let application = "DIP";
 
-    DIRS.set(
-        ProjectDirs::from("", "ALinuxPerson", application)
-            .context("could not find project directories")?,
-    )
-    .unwrap_or_else(|_| panic!("`DIRS` already initialized"));
+    let dirs = ProjectDirs::from("", "ALinuxPerson", application)
+        .context("could not find project directories")?;
+
+    // another thread may have located them in the meantime, which is just as good
+    let _ = DIRS.set(dirs);
 
     Ok(())
 }
 
+/// The project directories. Panics if [`initialize`] wasn't called.
 pub fn dirs() -> &'static ProjectDirs {
     DIRS.get().unwrap()
 }
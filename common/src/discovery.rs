@@ -0,0 +1,251 @@
+//! Discovery of remotes on the local network. Remotes periodically broadcast a beacon over UDP
+//! carrying their port and instance name, which hosts listen for. A remote whose beacons stop
+//! coming is forgotten after a few of them were missed.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time;
+
+/// The UDP port that beacons are broadcast to.
+pub const DISCOVERY_PORT: u16 = 49132;
+
+/// How often a remote broadcasts its beacon.
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long to listen for beacons before giving up on discovering any remote.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a remote may go without broadcasting a beacon before it is forgotten, which allows for
+/// a couple of beacons to get lost.
+pub const REMOTE_TIMEOUT: Duration = BEACON_INTERVAL.saturating_mul(3);
+
+/// How long to wait before receiving again after failing to receive a beacon, as the cause of that
+/// usually persists for a moment.
+const RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+const SERVICE: &str = "dip_remote";
+
+/// The payload of a beacon, sent as JSON.
+#[derive(Serialize, Deserialize)]
+struct Beacon {
+    service: String,
+    name: String,
+    port: u16,
+}
+
+/// A remote which was discovered on the local network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Remote {
+    pub name: String,
+    pub address: SocketAddr,
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.address)
+    }
+}
+
+/// Spawns a task which broadcasts a beacon announcing the remote named `name`, which accepts
+/// host connections on `port`, every [`BEACON_INTERVAL`].
+pub async fn announce(name: String, port: u16) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("failed to bind beacon socket")?;
+    socket
+        .set_broadcast(true)
+        .context("failed to enable broadcasting on beacon socket")?;
+
+    let beacon = serde_json::to_vec(&Beacon {
+        service: SERVICE.to_owned(),
+        name,
+        port,
+    })
+    .context("failed to serialize beacon")?;
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(BEACON_INTERVAL);
+        let mut failing = false;
+
+        loop {
+            interval.tick().await;
+
+            // only log the first of a run of failures, as the network may be down for a while
+            match socket
+                .send_to(&beacon, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))
+                .await
+            {
+                Ok(_) => failing = false,
+                Err(error) if !failing => {
+                    tracing::warn!("failed to broadcast beacon: {error}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// A remote along with when its last beacon was received.
+struct Seen {
+    remote: Remote,
+    at: Instant,
+}
+
+/// Forgets the remotes which haven't broadcast a beacon for [`REMOTE_TIMEOUT`].
+fn forget_gone(remotes: &mut Vec<Seen>) {
+    remotes.retain(|seen| {
+        let gone = seen.at.elapsed() > REMOTE_TIMEOUT;
+
+        if gone {
+            tracing::info!("lost remote {}, which stopped broadcasting", seen.remote);
+        }
+
+        !gone
+    });
+}
+
+/// The remotes discovered so far by a task which listens for beacons.
+#[derive(Clone)]
+pub struct Discovery {
+    remotes: Arc<Mutex<Vec<Seen>>>,
+
+    /// Notified whenever a remote is discovered.
+    discovered: Arc<Notify>,
+}
+
+impl Discovery {
+    /// Spawns a task which listens for beacons until the returned handle is dropped.
+    pub fn start() -> anyhow::Result<Self> {
+        let socket = Self::bind().context("failed to bind discovery socket")?;
+        let remotes = Arc::new(Mutex::new(Vec::new()));
+        let discovered = Arc::new(Notify::new());
+        let discovery = Self {
+            remotes: Arc::clone(&remotes),
+            discovered: Arc::clone(&discovered),
+        };
+        let remotes = Arc::downgrade(&remotes);
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let mut failing = false;
+
+            loop {
+                // only log the first of a run of failures, like when broadcasting
+                let (length, from) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => {
+                        failing = false;
+                        received
+                    }
+                    Err(error) => {
+                        if !failing {
+                            tracing::warn!("failed to receive beacon: {error}");
+                            failing = true;
+                        }
+
+                        if remotes.strong_count() == 0 {
+                            break;
+                        }
+
+                        time::sleep(RECEIVE_BACKOFF).await;
+                        continue;
+                    }
+                };
+
+                let Some(remotes) = remotes.upgrade() else {
+                    break;
+                };
+
+                let beacon = match serde_json::from_slice::<Beacon>(&buf[..length]) {
+                    Ok(beacon) if beacon.service == SERVICE => beacon,
+                    _ => {
+                        tracing::trace!("ignoring datagram from {from} which isn't a beacon");
+                        continue;
+                    }
+                };
+
+                let remote = Remote {
+                    name: beacon.name,
+                    address: SocketAddr::new(from.ip(), beacon.port),
+                };
+                let mut remotes = remotes.lock().unwrap();
+                forget_gone(&mut remotes);
+
+                // remotes are kept in the order they were first discovered in, but a remote which
+                // comes back with a different address replaces its old one
+                match remotes
+                    .iter_mut()
+                    .find(|known| known.remote.name == remote.name)
+                {
+                    Some(known) => {
+                        if known.remote != remote {
+                            tracing::info!("remote {} moved to {}", remote.name, remote.address);
+                            known.remote = remote;
+                        }
+
+                        known.at = Instant::now();
+                    }
+                    None => {
+                        tracing::info!("discovered remote {remote}");
+                        remotes.push(Seen {
+                            remote,
+                            at: Instant::now(),
+                        });
+                        discovered.notify_waiters();
+                    }
+                }
+            }
+        });
+
+        Ok(discovery)
+    }
+
+    fn bind() -> anyhow::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+        // several hosts may run on the same machine, each of which should see every beacon
+        socket.set_reuse_address(true)?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).into())?;
+
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    /// The remotes discovered so far which are still broadcasting, in the order they were
+    /// discovered in.
+    pub fn remotes(&self) -> Vec<Remote> {
+        let mut remotes = self.remotes.lock().unwrap();
+        forget_gone(&mut remotes);
+
+        remotes.iter().map(|seen| seen.remote.clone()).collect()
+    }
+
+    /// Waits until at least one remote is discovered, or until `timeout` elapses.
+    pub async fn wait(&self, timeout: Duration) -> Vec<Remote> {
+        let _ = time::timeout(timeout, async {
+            loop {
+                // waiting starts before checking, so that a remote discovered in between counts
+                let discovered = self.discovered.notified();
+
+                if !self.remotes().is_empty() {
+                    break;
+                }
+
+                discovered.await;
+            }
+        })
+        .await;
+
+        self.remotes()
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{ReadFrom, WriteTo};
+use anyhow::Context;
+use std::fmt;
+use std::ops::ControlFlow;
+
+/// The opcodes of the Discord IPC protocol, along with the ones dip uses between host and remote.
+pub mod opcode {
+    pub const HANDSHAKE: u32 = 0;
+    pub const FRAME: u32 = 1;
+    pub const CLOSE: u32 = 2;
+    pub const PING: u32 = 3;
+    pub const PONG: u32 = 4;
+
+    /// Sent between host and remote only, if both support the
+    /// [`HEARTBEAT`](crate::link::HEARTBEAT) capability. Never forwarded to Discord or the app.
+    pub const HEARTBEAT: u32 = 0x6469_7000;
+
+    /// The answer to [`HEARTBEAT`].
+    pub const HEARTBEAT_ACK: u32 = 0x6469_7001;
+}
+
+/// The codes of the Close frames sent by dip itself, which are outside of the range used by
+/// Discord.
+pub mod close_code {
+    use std::ops::RangeInclusive;
+
+    pub const REMOTE_UNREACHABLE: u32 = 4900;
+    pub const DISCORD_NOT_RUNNING: u32 = 4901;
+    pub const SHUTTING_DOWN: u32 = 4902;
+    pub const TOO_MANY_SESSIONS: u32 = 4903;
+
+    /// Every code which may be sent by dip.
+    pub const DIP: RangeInclusive<u32> = 4900..=4999;
+}
+
+/// Which way a frame is going through a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the app to Discord.
+    Outgoing,
+
+    /// From Discord to the app.
+    Incoming,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Outgoing => "outgoing",
+            Self::Incoming => "incoming",
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A single packet of the Discord IPC protocol. On the wire, it is the opcode and the length of
+/// the payload (both little endian) followed by the payload, which is usually JSON.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub const HEADER_LENGTH: usize = 8;
+
+    /// The largest payload which is read. Discord and apps send payloads of a few kilobytes at
+    /// most, so a larger one is garbage, or a peer trying to make us allocate memory.
+    pub const MAX_PAYLOAD_LENGTH: u32 = 1024 * 1024;
+
+    pub fn new(opcode: u32, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            opcode,
+            payload: payload.into(),
+        }
+    }
+
+    /// A Close frame with `code` and `message`, in the same format Discord uses.
+    pub fn close(code: u32, message: &str) -> Self {
+        let payload = serde_json::json!({ "code": code, "message": message });
+        Self::new(opcode::CLOSE, payload.to_string())
+    }
+
+    /// The code of this frame if it is a Close frame.
+    pub fn close_code(&self) -> Option<u32> {
+        if self.opcode != opcode::CLOSE {
+            return None;
+        }
+
+        let payload = serde_json::from_slice::<serde_json::Value>(&self.payload).ok()?;
+        payload.get("code")?.as_u64()?.try_into().ok()
+    }
+
+    /// The client id of the app which sent this frame if it is a handshake.
+    pub fn client_id(&self) -> Option<String> {
+        if self.opcode != opcode::HANDSHAKE {
+            return None;
+        }
+
+        let payload = serde_json::from_slice::<serde_json::Value>(&self.payload).ok()?;
+
+        match payload.get("client_id")? {
+            serde_json::Value::String(client_id) => Some(client_id.clone()),
+            client_id => Some(client_id.to_string()),
+        }
+    }
+
+    /// Reads a frame, returning `None` if the stream ended. A frame whose payload is larger than
+    /// [`Frame::MAX_PAYLOAD_LENGTH`] is an error, which is returned before reading its payload.
+    pub async fn read<R: ReadFrom>(read_from: &R) -> anyhow::Result<Option<Self>> {
+        macro_rules! read_exact_or_return {
+            ($buf:expr, $error_message:expr) => {
+                match read_from.read_exact_or_break($buf).await {
+                    ControlFlow::Break(()) => return Ok(None),
+                    ControlFlow::Continue(result) => result.context($error_message)?,
+                }
+            };
+        }
+
+        let mut header = [0; Self::HEADER_LENGTH];
+        read_exact_or_return!(&mut header, "failed to read header buffer");
+
+        let opcode = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        tracing::debug!(?opcode, ?length, "frame header");
+        anyhow::ensure!(
+            length <= Self::MAX_PAYLOAD_LENGTH,
+            "received a frame of {length} bytes, which is too large"
+        );
+
+        let mut payload = vec![0; length as usize];
+        read_exact_or_return!(&mut payload, "failed to read json content");
+
+        Ok(Some(Self { opcode, payload }))
+    }
+
+    pub async fn write<W: WriteTo>(&self, write_to: &W) -> anyhow::Result<()> {
+        write_to
+            .write_all(&self.to_bytes())
+            .await
+            .context("failed to write packet")
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_LENGTH + self.payload.len());
+        bytes.extend(self.opcode.to_le_bytes());
+        bytes.extend((self.payload.len() as u32).to_le_bytes());
+        bytes.extend(&self.payload);
+        bytes
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "opcode {}: \"{}\"",
+            self.opcode,
+            String::from_utf8_lossy(&self.payload)
+        )
+    }
+}
@@ -1,51 +1,23 @@
 #[macro_use]
 mod macros;
 
+pub mod access;
+pub mod activity;
+pub mod config;
 pub mod dirs;
+pub mod discovery;
+pub mod frame;
+pub mod link;
+pub mod logging;
+pub mod offline;
+pub mod preamble;
+pub mod proxy;
+pub mod reload;
+pub mod rewrite;
+pub mod script;
 pub mod serve;
+pub mod shutdown;
 pub mod utils;
-pub mod config {
-    use anyhow::Context;
-    use clap::Parser;
-    use figment::providers::{Format, Serialized, Toml};
-    use figment::Figment;
-    use serde::{Deserialize, Serialize};
-    use std::borrow::Cow;
-    use std::path::{Path, PathBuf};
-    use std::sync::OnceLock;
-
-    pub trait ConfigLike<'de>: Serialize + Deserialize<'de> + Parser + Sized {
-        const FILE_NAME: &'static str;
-
-        fn discord_ipc_path(&self) -> &Option<PathBuf>;
-
-        fn read() -> anyhow::Result<Self> {
-            Figment::new()
-                .merge(Serialized::defaults(Self::parse()))
-                .merge(Toml::file(Self::toml()))
-                .extract()
-                .context("failed to extract config")
-        }
-
-        fn toml() -> &'static Path {
-            static PATH: OnceLock<PathBuf> = OnceLock::new();
-
-            PATH.get_or_init(|| crate::dirs().config_dir().join(Self::FILE_NAME))
-        }
-
-        fn socket_path(
-            &self,
-            find_socket_fn: impl FnOnce() -> Option<PathBuf>,
-            on_error: &'static str,
-        ) -> anyhow::Result<Cow<Path>> {
-            self.discord_ipc_path()
-                .as_deref()
-                .map(Cow::Borrowed)
-                .or_else(|| Some(Cow::Owned(find_socket_fn()?)))
-                .context(on_error)
-        }
-    }
-}
 
 #[cfg(windows)]
 mod win;
@@ -61,14 +33,15 @@ mod unix {
     impl_WriteTo_for!(lt WriteHalf);
 }
 
-use anyhow::Context;
 use async_trait::async_trait;
-pub use config::ConfigLike;
+pub use config::{ConfigLike, Loader};
 pub use dirs::dirs;
+pub use frame::Frame;
+pub use proxy::Proxy;
 pub use serve::serve;
 use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
-use std::{env, io};
+use std::{env, io, process};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
 use tracing::Span;
 
@@ -79,38 +52,27 @@ pub trait ReadFrom: Sync {
     async fn readable(&self) -> io::Result<()>;
     fn try_read(&self, buf: &mut [u8]) -> io::Result<usize>;
 
-    fn try_read_exact(&self, mut buf: &mut [u8]) -> io::Result<()> {
+    async fn read_exact(&self, mut buf: &mut [u8]) -> io::Result<()> {
+        // bytes which were read before the read would block must not be read over again
         while !buf.is_empty() {
             match self.try_read(buf) {
-                Ok(0) => break,
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
                 Ok(n) => {
                     let tmp = buf;
                     buf = &mut tmp[n..];
                 }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.readable().await?,
                 Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
                 Err(e) => return Err(e),
             }
         }
 
-        if !buf.is_empty() {
-            Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "failed to fill whole buffer",
-            ))
-        } else {
-            Ok(())
-        }
-    }
-
-    async fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
-        let result = self.try_read_exact(buf);
-
-        if let Err(io::ErrorKind::WouldBlock) = result.as_ref().map_err(|e| e.kind()) {
-            self.readable().await?;
-            self.read_exact(buf).await
-        } else {
-            result
-        }
+        Ok(())
     }
 
     async fn read_exact_or_break(&self, buf: &mut [u8]) -> ControlFlow<(), io::Result<()>> {
@@ -132,7 +94,9 @@ pub trait WriteTo: Sync {
     async fn writable(&self) -> io::Result<()>;
     fn try_write(&self, buf: &[u8]) -> io::Result<usize>;
 
-    fn try_write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+    async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        // likewise, bytes which were written before the write would block must not be written
+        // again
         while !buf.is_empty() {
             match self.try_write(buf) {
                 Ok(0) => {
@@ -142,32 +106,13 @@ pub trait WriteTo: Sync {
                     ));
                 }
                 Ok(n) => buf = &buf[n..],
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.writable().await?,
                 Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
                 Err(e) => return Err(e),
             }
         }
-        Ok(())
-    }
-
-    async fn write_all(&self, buf: &[u8]) -> io::Result<()> {
-        let result = self.try_write_all(buf);
-
-        if let Err(io::ErrorKind::WouldBlock) = result.as_ref().map_err(|e| e.kind()) {
-            self.writable().await?;
-            self.write_all(buf).await
-        } else {
-            Ok(())
-        }
-    }
-
-    async fn write_all_or_break(&self, buf: &[u8]) -> ControlFlow<(), io::Result<()>> {
-        let result = self.write_all(buf).await;
 
-        if let Err(io::ErrorKind::UnexpectedEof) = result.as_ref().map_err(|e| e.kind()) {
-            ControlFlow::Break(())
-        } else {
-            ControlFlow::Continue(result)
-        }
+        Ok(())
     }
 }
 
@@ -187,67 +132,33 @@ pub fn find_socket(mut test_fn: impl FnMut(&Path) -> bool) -> Option<PathBuf> {
         .find(|socket_path| test_fn(socket_path))
 }
 
-#[tracing::instrument(skip_all)]
-pub async fn read_from_then_write_to<R: ReadFrom, W: WriteTo>(
-    read_from: &R,
-    write_to: &W,
-    read_from_name: &str,
-    write_to_name: &str,
-) -> ControlFlow<anyhow::Result<()>> {
-    macro_rules! read_exact_or_break {
-        ($var:expr, $buf:expr, $error_message:expr) => {
-            match $var.read_exact_or_break($buf).await {
-                ControlFlow::Break(()) => return ControlFlow::Break(Ok(())),
-                ControlFlow::Continue(Ok(())) => (),
-                ControlFlow::Continue(Err(error)) => {
-                    return ControlFlow::Break(Err(error).context($error_message))
-                }
-            }
-        };
-    }
-
-    let mut header_buffer = [0; 8];
-    read_exact_or_break!(
-        read_from,
-        &mut header_buffer,
-        "failed to read header buffer"
-    );
-
-    let header = u64::from_le_bytes(header_buffer);
-    tracing::debug!(?header, "buffer");
-
-    // length is the last 4 bytes of the header portion of the packet
-    let length = (header >> 32) as u32;
-    tracing::debug!(?length, "packet length");
-
-    let mut json_content = vec![0; length as usize];
-    read_exact_or_break!(read_from, &mut json_content, "failed to read json content");
+pub fn common<'de, C: ConfigLike<'de>>() -> anyhow::Result<(Span, Loader<C>, C)> {
+    logging::initialize();
+    dirs::initialize()?;
 
-    let mut packet = Vec::with_capacity(8 + length as usize);
-    packet.extend(header_buffer);
-    packet.extend(json_content);
-    tracing::trace!(
-        "{read_from_name} -> {write_to_name}: \"{}\"",
-        String::from_utf8_lossy(&packet)
-    );
+    let loader = C::loader()?;
 
-    match write_to.write_all_or_break(&packet).await {
-        ControlFlow::Break(()) => ControlFlow::Break(Ok(())),
-        ControlFlow::Continue(Ok(())) => ControlFlow::Continue(()),
-        ControlFlow::Continue(Err(error)) => {
-            ControlFlow::Break(Err(error).context("failed to write packet"))
-        }
+    // an invalid configuration is reported rather than printed, just like it is on startup
+    if loader.args().print_config {
+        loader.load()?.validate()?;
+        print!("{}", loader.show()?);
+        process::exit(0);
     }
-}
-
-pub fn common<'de, C: ConfigLike<'de>>() -> anyhow::Result<(Span, C)> {
-    tracing_subscriber::fmt::init();
-    dirs::initialize()?;
 
-    tracing::debug!("config file location is {}", C::toml().display());
+    let file = loader.file();
+    tracing::info!(
+        "config file location is {} (from {})",
+        file.path.display(),
+        file.source
+    );
 
     let span = tracing::info_span!("resolve config");
-    let config = C::read()?;
+    let config = loader.load()?;
+    config.validate()?;
+
+    if let Some(filter) = config.log() {
+        logging::set_filter(Some(filter))?;
+    }
 
-    Ok((span, config))
+    Ok((span, loader, config))
 }
@@ -0,0 +1,175 @@
+//! Options for the link between host and remote, which is where a dead peer can go unnoticed.
+//!
+//! If both sides support the [`HEARTBEAT`] capability, each side sends a heartbeat every
+//! interval, which the other side acknowledges straight away. A link which stays silent for
+//! longer than the timeout is considered dead, and its session is torn down.
+
+use crate::rewrite::{self, RewriteRule};
+use crate::script::{self, Script};
+use crate::serve::ErrorKind;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// The capability of understanding heartbeat frames.
+pub const HEARTBEAT: &str = "heartbeat";
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The configuration of the link between host and remote and of the sessions over it, shared by
+/// both of them.
+#[derive(Clone, Default, Serialize, Deserialize, Args)]
+pub struct LinkConfig {
+    /// How often, in seconds, to send heartbeats over the link between host and remote. 0
+    /// disables sending heartbeats, and with them noticing that the link is dead. If not
+    /// specified, it will default to 10.
+    #[clap(long)]
+    pub heartbeat_interval: Option<u64>,
+
+    /// How long, in seconds, the link may stay silent before it is considered dead and the
+    /// session is torn down. It must be longer than the heartbeat interval. If not specified, it
+    /// will default to 30.
+    #[clap(long)]
+    pub heartbeat_timeout: Option<u64>,
+
+    /// How long, in seconds, the link may be idle before TCP keepalive probes are sent. If not
+    /// specified, TCP keepalive is not enabled.
+    #[clap(long)]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Whether or not to set `TCP_NODELAY` on the link, so that frames are sent straight away
+    /// instead of being buffered.
+    #[clap(long)]
+    #[serde(default)]
+    pub tcp_nodelay: bool,
+
+    /// How long, in milliseconds, to keep forwarding the other direction of a session once one
+    /// side has closed, so that a final Close frame can still get through. If not specified, the
+    /// session is torn down straight away.
+    #[clap(long)]
+    pub linger: Option<u64>,
+
+    /// The kinds of errors which stop serving altogether, rather than only ending the session they
+    /// happened in. If not specified, no error is fatal.
+    #[clap(long, value_delimiter = ',')]
+    #[serde(default)]
+    pub fatal_errors: Vec<ErrorKind>,
+
+    /// How long, in milliseconds, to hold back the activity updates of an app after one was
+    /// forwarded. Updates in between are answered straight away, and only the latest of them is
+    /// forwarded once the time is up. If not specified, every update is forwarded straight away.
+    #[clap(long)]
+    pub coalesce_activity: Option<u64>,
+
+    /// Whether or not to skip the activity updates of an app which are the same as the last one,
+    /// apart from their nonce. They are answered straight away instead. Default value is "true".
+    #[clap(long)]
+    pub dedup_activity: Option<bool>,
+
+    /// A Rhai script which can inspect, change, drop and inject the frames of every session. If
+    /// not specified, no script is run.
+    #[clap(long)]
+    pub script: Option<PathBuf>,
+
+    /// How long, in milliseconds, each call into the script may run before it is stopped. If not
+    /// specified, it will default to 50.
+    #[clap(long)]
+    pub script_budget: Option<u64>,
+
+    /// The rules to rewrite the commands of apps with, in order. Only set in the configuration
+    /// file, as `[[rewrite]]` tables.
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rewrite: Vec<RewriteRule>,
+}
+
+impl LinkConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let options = self.options();
+
+        // a healthy link is only heard from once every interval, so it would always time out
+        if let Some(interval) = options.heartbeat_interval {
+            anyhow::ensure!(
+                options.heartbeat_timeout > interval,
+                "heartbeat_timeout ({} seconds) must be longer than heartbeat_interval ({} seconds)",
+                options.heartbeat_timeout.as_secs(),
+                interval.as_secs()
+            );
+        }
+
+        rewrite::validate(&self.rewrite)?;
+
+        // compiled here as well, so that a reload with a broken script keeps the current config
+        self.script().map(drop)
+    }
+
+    pub fn coalesce_activity(&self) -> Option<Duration> {
+        self.coalesce_activity
+            .filter(|&window| window > 0)
+            .map(Duration::from_millis)
+    }
+
+    /// Compiles the script, if there is one.
+    pub fn script(&self) -> anyhow::Result<Option<Script>> {
+        let budget = self
+            .script_budget
+            .map_or(script::DEFAULT_BUDGET, Duration::from_millis);
+
+        self.script
+            .as_deref()
+            .map(|path| Script::load(path, budget))
+            .transpose()
+    }
+
+    pub fn options(&self) -> LinkOptions {
+        LinkOptions {
+            heartbeat_interval: match self.heartbeat_interval {
+                Some(0) => None,
+                Some(interval) => Some(Duration::from_secs(interval)),
+                None => Some(DEFAULT_HEARTBEAT_INTERVAL),
+            },
+            heartbeat_timeout: self
+                .heartbeat_timeout
+                .map_or(DEFAULT_HEARTBEAT_TIMEOUT, Duration::from_secs),
+            tcp_keepalive: self.tcp_keepalive.map(Duration::from_secs),
+            tcp_nodelay: self.tcp_nodelay,
+            linger: self.linger.map_or(Duration::ZERO, Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkOptions {
+    /// How often to send heartbeats, or `None` to not send any.
+    pub heartbeat_interval: Option<Duration>,
+    pub heartbeat_timeout: Duration,
+    pub tcp_keepalive: Option<Duration>,
+    pub tcp_nodelay: bool,
+
+    /// How long to wait for the other direction of a session to end once one has.
+    pub linger: Duration,
+}
+
+impl Default for LinkOptions {
+    fn default() -> Self {
+        LinkConfig::default().options()
+    }
+}
+
+impl LinkOptions {
+    /// Applies the TCP options to `stream`.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.tcp_nodelay)?;
+
+        if let Some(time) = self.tcp_keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Context;
+use std::env;
+use std::sync::OnceLock;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+const DEFAULT_FILTER: &str = "info";
+
+static HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Initializes logging with the filter in the `RUST_LOG` environment variable, falling back to
+/// `info`. The filter can be replaced afterwards with [`set_filter`].
+///
+/// Does nothing if a global subscriber is already set, such as by an app which embeds dip, in
+/// which case logging is left to it, and [`set_filter`] fails.
+pub fn initialize() {
+    if HANDLE.get().is_some() {
+        return;
+    }
+
+    let (filter, handle) = reload::Layer::new(default_filter());
+    let initialized = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .try_init();
+
+    if initialized.is_ok() {
+        let _ = HANDLE.set(handle);
+    }
+}
+
+fn default_filter() -> EnvFilter {
+    env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .and_then(|filter| EnvFilter::try_new(filter).ok())
+        .unwrap_or_else(|| EnvFilter::new(DEFAULT_FILTER))
+}
+
+/// Replaces the current filter with `filter`, using the same syntax as `RUST_LOG`. If `filter` is
+/// `None`, the filter from `RUST_LOG` is used instead.
+pub fn set_filter(filter: Option<&str>) -> anyhow::Result<()> {
+    let filter = match filter {
+        Some(filter) => {
+            EnvFilter::try_new(filter).with_context(|| format!("invalid log filter `{filter}`"))?
+        }
+        None => default_filter(),
+    };
+
+    HANDLE
+        .get()
+        .context("logging is not initialized")?
+        .reload(filter)
+        .context("failed to replace log filter")
+}
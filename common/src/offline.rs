@@ -0,0 +1,121 @@
+//! Emulation of Discord for apps whose session couldn't be served, so that they carry on as if
+//! Discord was there. Their latest activity is kept, and set for real once the session can be
+//! served after all.
+
+use crate::activity;
+use crate::frame::opcode;
+use crate::Frame;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// How often to try serving a session which is offline.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The error code answered to commands which can't be emulated, which is Discord's code for an
+/// unknown error.
+const UNKNOWN_ERROR: u32 = 1000;
+
+/// What to do with a frame from the app.
+pub enum Answer {
+    /// Send this frame back to the app.
+    Reply(Frame),
+
+    /// The app wants to close the session.
+    Close,
+}
+
+/// Stands in for Discord during a session.
+pub struct Emulator {
+    client_id: String,
+    activity: Option<Frame>,
+}
+
+impl Emulator {
+    pub fn new(handshake: &Frame) -> Self {
+        Self {
+            client_id: handshake
+                .client_id()
+                .unwrap_or_else(|| "unknown".to_owned()),
+            activity: None,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// The READY event which answers the handshake.
+    pub fn ready(&self) -> Frame {
+        let payload = json!({
+            "cmd": "DISPATCH",
+            "evt": "READY",
+            "nonce": null,
+            "data": {
+                "v": 1,
+                "config": {
+                    "cdn_host": "cdn.discordapp.com",
+                    "api_endpoint": "//discord.com/api",
+                    "environment": "production",
+                },
+                "user": {
+                    "id": "0",
+                    "username": "dip",
+                    "discriminator": "0",
+                    "global_name": "dip (offline)",
+                    "avatar": null,
+                    "bot": false,
+                    "flags": 0,
+                    "premium_type": 0,
+                },
+            },
+        });
+
+        Frame::new(opcode::FRAME, payload.to_string())
+    }
+
+    pub fn answer(&mut self, frame: Frame) -> Answer {
+        match frame.opcode {
+            opcode::CLOSE => return Answer::Close,
+            opcode::PING => return Answer::Reply(Frame::new(opcode::PONG, frame.payload)),
+            _ => {}
+        }
+
+        if let Some(command) = activity::set_activity(&frame) {
+            // only the latest activity matters, as it replaces every one before it
+            tracing::debug!("keeping activity of {} until back online", self.client_id);
+            self.activity = Some(frame);
+
+            return Answer::Reply(activity::success(&command));
+        }
+
+        let command = serde_json::from_slice::<Value>(&frame.payload).unwrap_or_default();
+        let cmd = command.get("cmd").cloned().unwrap_or(Value::Null);
+        let nonce = command.get("nonce").cloned().unwrap_or(Value::Null);
+
+        let payload = json!({
+            "cmd": cmd,
+            "evt": "ERROR",
+            "nonce": nonce,
+            "data": {
+                "code": UNKNOWN_ERROR,
+                "message": "Discord is unreachable, so dip is answering in its place",
+            },
+        });
+
+        Answer::Reply(Frame::new(opcode::FRAME, payload.to_string()))
+    }
+
+    /// The latest SET_ACTIVITY frame, if there was any.
+    pub fn take_activity(&mut self) -> Option<Frame> {
+        self.activity.take()
+    }
+}
+
+/// The nonce of a command or response, if it has one.
+pub fn nonce(frame: &Frame) -> Option<Value> {
+    serde_json::from_slice::<Value>(&frame.payload)
+        .ok()?
+        .get("nonce")
+        .filter(|nonce| !nonce.is_null())
+        .cloned()
+}
@@ -0,0 +1,268 @@
+//! The preamble which the host and remote exchange right after connecting, before any Discord
+//! frames are forwarded. Each side sends a hello carrying the protocol version it speaks, the
+//! capabilities it supports and optionally its name, so that features can be added without
+//! breaking older peers, and peers which can't talk to each other fail with a clear error.
+//!
+//! On the wire, a hello is [`MAGIC`] and the length of the payload (little endian) followed by the
+//! payload, which is JSON.
+
+use crate::{ReadFrom, WriteTo};
+use anyhow::Context;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The bytes every hello starts with. Discord frames start with their opcode instead, which is
+/// never this large.
+pub const MAGIC: [u8; 4] = *b"dip\0";
+
+/// The version of the protocol spoken by this build.
+pub const PROTOCOL_VERSION: Version = Version::new(0, 2);
+
+/// The oldest version of the protocol which this build can talk to.
+pub const MIN_PROTOCOL_VERSION: Version = Version::new(0, 2);
+
+/// The version spoken by peers which don't send a preamble at all.
+const LEGACY_VERSION: Version = Version::new(0, 1);
+
+/// The capabilities supported by this build.
+pub const CAPABILITIES: &[&str] = &[crate::link::HEARTBEAT];
+
+/// Hellos are tiny, so anything larger than this is garbage.
+const MAX_HELLO_LENGTH: u32 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s
+            .split_once('.')
+            .with_context(|| format!("`{s}` is not a version"))?;
+
+        Ok(Self {
+            major: major.parse().context("failed to parse major version")?,
+            minor: minor.parse().context("failed to parse minor version")?,
+        })
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Self::from_str(&String::deserialize(deserializer)?)
+            .map_err(|e| D::Error::custom(format!("{e:#}")))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: Version,
+
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// When this hello was sent, in milliseconds since the unix epoch, so that the peer can tell
+    /// how far apart the clocks of both sides are. Set when it is written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<i64>,
+}
+
+impl Hello {
+    /// A hello for this build, optionally carrying the name of this instance.
+    pub fn new(name: Option<String>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|&c| c.to_owned()).collect(),
+            name,
+            time: None,
+        }
+    }
+
+    async fn read<R: ReadFrom>(read_from: &R, peer_name: &str) -> anyhow::Result<Self> {
+        // peers which predate the preamble send discord frames straight away, or in the case of
+        // an old remote, forward our hello to discord which then hangs up
+        let legacy = || {
+            format!(
+                "{peer_name} is v{LEGACY_VERSION}, need ≥{MIN_PROTOCOL_VERSION} (it sent no \
+                 preamble)"
+            )
+        };
+
+        let mut header = [0; 8];
+        read_from.read_exact(&mut header).await.map_err(|error| {
+            if error.kind() == io::ErrorKind::UnexpectedEof {
+                anyhow::anyhow!(legacy()).context("connection closed during the preamble")
+            } else {
+                anyhow::Error::new(error).context("failed to read preamble")
+            }
+        })?;
+
+        anyhow::ensure!(header[..4] == MAGIC, legacy());
+
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        anyhow::ensure!(
+            length <= MAX_HELLO_LENGTH,
+            "{peer_name} sent a preamble of {length} bytes, which is too large"
+        );
+
+        let mut payload = vec![0; length as usize];
+        read_from
+            .read_exact(&mut payload)
+            .await
+            .context("failed to read preamble")?;
+
+        serde_json::from_slice(&payload)
+            .with_context(|| format!("{peer_name} sent an invalid preamble"))
+    }
+
+    async fn write<W: WriteTo>(&self, write_to: &W) -> anyhow::Result<()> {
+        let hello = Self {
+            time: Some(now()),
+            ..self.clone()
+        };
+        let payload = serde_json::to_vec(&hello).context("failed to serialize preamble")?;
+        let mut bytes = Vec::with_capacity(8 + payload.len());
+        bytes.extend(MAGIC);
+        bytes.extend((payload.len() as u32).to_le_bytes());
+        bytes.extend(payload);
+
+        write_to
+            .write_all(&bytes)
+            .await
+            .context("failed to write preamble")
+    }
+}
+
+/// The current time, in milliseconds since the unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+/// The outcome of a preamble.
+#[derive(Clone, Debug)]
+pub struct Negotiated {
+    /// The hello sent by the peer.
+    pub peer: Hello,
+
+    /// The capabilities supported by both sides.
+    pub capabilities: Vec<String>,
+
+    /// How far the clock of the peer is ahead of ours, in milliseconds, if it told the time. As
+    /// this is measured from when its hello was sent and received, it is off by however long the
+    /// hello took to arrive.
+    pub clock_offset: Option<i64>,
+}
+
+impl Negotiated {
+    fn new(ours: &Hello, peer: Hello, received: i64, peer_name: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            peer.version >= MIN_PROTOCOL_VERSION,
+            "{peer_name} is v{}, need ≥{MIN_PROTOCOL_VERSION}",
+            peer.version
+        );
+        anyhow::ensure!(
+            peer.version.major == ours.version.major,
+            "{peer_name} is v{}, which is incompatible with v{}",
+            peer.version,
+            ours.version
+        );
+
+        let capabilities = ours
+            .capabilities
+            .iter()
+            .filter(|capability| peer.capabilities.contains(capability))
+            .cloned()
+            .collect();
+
+        let clock_offset = peer.time.map(|time| time - received);
+
+        Ok(Self {
+            peer,
+            capabilities,
+            clock_offset,
+        })
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+impl fmt::Display for Negotiated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.peer.name {
+            write!(f, "{name}, ")?;
+        }
+
+        write!(f, "v{}", self.peer.version)?;
+
+        if !self.capabilities.is_empty() {
+            write!(f, ", supporting {}", self.capabilities.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the preamble as the side which connected, which sends its hello first.
+pub async fn initiate<R: ReadFrom, W: WriteTo>(
+    read_from: &R,
+    write_to: &W,
+    ours: &Hello,
+    peer_name: &str,
+) -> anyhow::Result<Negotiated> {
+    ours.write(write_to).await?;
+    let peer = Hello::read(read_from, peer_name).await?;
+    Negotiated::new(ours, peer, now(), peer_name)
+}
+
+/// Runs the preamble as the side which accepted the connection. Our hello is sent back even if the
+/// peer turns out to be incompatible, so that it can tell why.
+pub async fn respond<R: ReadFrom, W: WriteTo>(
+    read_from: &R,
+    write_to: &W,
+    ours: &Hello,
+    peer_name: &str,
+) -> anyhow::Result<Negotiated> {
+    let peer = Hello::read(read_from, peer_name).await?;
+    let received = now();
+    ours.write(write_to).await?;
+    Negotiated::new(ours, peer, received, peer_name)
+}
@@ -0,0 +1,189 @@
+//! An embeddable proxy, for apps which run either side of dip themselves rather than through
+//! `dip_host` or `dip_remote`. Unlike [`common`](crate::common), nothing global is touched: logging
+//! is left to the app, and no configuration file is read unless the app does so itself.
+
+use crate::link::LinkConfig;
+use crate::serve::{
+    self, Displayable, ServableListener, ServableStream, ServeHooks, SessionInfo, Sessions,
+    StreamConnectTo,
+};
+use crate::shutdown::Shutdown;
+use anyhow::Context;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+pub struct Proxy;
+
+impl Proxy {
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder {
+            listen: (),
+            connect: (),
+            new_client_name: "new client",
+            stream_name: "stream",
+            hooks: ServeHooks::default(),
+        }
+    }
+}
+
+/// Where a proxy accepts new clients, and with which kind of listener.
+pub struct Listen<L, LS> {
+    bind_to: LS,
+    listener: PhantomData<fn() -> L>,
+}
+
+/// Where a proxy connects the sessions of new clients to, and with which kind of stream.
+pub struct Connect<S, SS> {
+    connect_to: StreamConnectTo<SS>,
+    stream: PhantomData<fn() -> S>,
+}
+
+/// Builds a proxy. It can only be started once both [`ProxyBuilder::listen`] and
+/// [`ProxyBuilder::connect`] were called.
+pub struct ProxyBuilder<L = (), C = ()> {
+    listen: L,
+    connect: C,
+    new_client_name: &'static str,
+    stream_name: &'static str,
+    hooks: ServeHooks,
+}
+
+impl<L, C> ProxyBuilder<L, C> {
+    /// The names of both sides of every session, as they appear in logs.
+    pub fn names(mut self, new_client_name: &'static str, stream_name: &'static str) -> Self {
+        self.new_client_name = new_client_name;
+        self.stream_name = stream_name;
+        self
+    }
+
+    /// Replaces the hooks set so far.
+    pub fn hooks(mut self, hooks: ServeHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Applies everything in `config` which is about sessions onto the hooks set so far.
+    pub fn config(mut self, config: &LinkConfig) -> anyhow::Result<Self> {
+        self.hooks = self.hooks.link_config(config)?;
+        Ok(self)
+    }
+}
+
+impl<C> ProxyBuilder<(), C> {
+    /// Accepts new clients with a listener of type `L` bound to `bind_to`.
+    pub fn listen<L, LS>(self, bind_to: LS) -> ProxyBuilder<Listen<L, LS>, C>
+    where
+        LS: Send + 'static,
+        L: ServableListener<LS>,
+    {
+        ProxyBuilder {
+            listen: Listen {
+                bind_to,
+                listener: PhantomData,
+            },
+            connect: self.connect,
+            new_client_name: self.new_client_name,
+            stream_name: self.stream_name,
+            hooks: self.hooks,
+        }
+    }
+}
+
+impl<L> ProxyBuilder<L, ()> {
+    /// Connects every session with a stream of type `S` to the first of the addresses returned by
+    /// `connect_to` which answers, which is called once for every session.
+    pub fn connect<S, SS>(
+        self,
+        connect_to: impl Fn() -> Vec<SS> + Send + Sync + 'static,
+    ) -> ProxyBuilder<L, Connect<S, SS>>
+    where
+        SS: Send + 'static,
+        S: ServableStream<SS>,
+    {
+        ProxyBuilder {
+            listen: self.listen,
+            connect: Connect {
+                connect_to: Arc::new(connect_to),
+                stream: PhantomData,
+            },
+            new_client_name: self.new_client_name,
+            stream_name: self.stream_name,
+            hooks: self.hooks,
+        }
+    }
+}
+
+impl<L, LS, S, SS> ProxyBuilder<Listen<L, LS>, Connect<S, SS>>
+where
+    LS: Displayable + Send + 'static,
+    L: ServableListener<LS> + Send + Sync + 'static,
+    L::Stream: Send + 'static,
+    L::SocketAddr: Debug + Send,
+    SS: Displayable + Send + 'static,
+    S: ServableStream<SS> + Send + 'static,
+{
+    /// Binds the listener, then serves it in a task of its own until the returned handle is shut
+    /// down, or a fatal error happens. If binding created a file, such as a unix socket, it is
+    /// registered with [`Shutdown::remove_on_exit`] of the hooks.
+    pub async fn start(self) -> anyhow::Result<ProxyHandle> {
+        let bound_file = L::bound_file(&self.listen.bind_to);
+        let listener = serve::bind::<L, LS>(self.listen.bind_to).await?;
+        let shutdown = self.hooks.shutdown.clone();
+
+        if let Some(path) = bound_file {
+            shutdown.remove_on_exit(path);
+        }
+
+        let sessions = self.hooks.sessions.clone();
+        let task = tokio::spawn(serve::serve_on::<L, S, LS, SS>(
+            listener,
+            self.connect.connect_to,
+            self.new_client_name,
+            self.stream_name,
+            self.hooks,
+        ));
+
+        Ok(ProxyHandle {
+            shutdown,
+            sessions,
+            task,
+        })
+    }
+}
+
+/// A running proxy. Dropping it leaves the proxy running, until a shutdown is requested through
+/// the [`Shutdown`] in its hooks.
+pub struct ProxyHandle {
+    shutdown: Shutdown,
+    sessions: Sessions,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl ProxyHandle {
+    /// Every session being served.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.list()
+    }
+
+    /// Whether the proxy stopped serving, such as because of a fatal error.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Stops accepting new clients, and waits for the active sessions to close.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.shutdown.request();
+        self.join().await
+    }
+
+    /// Waits until the proxy stops serving, returning the fatal error which stopped it if there
+    /// was one. The files registered with [`Shutdown::remove_on_exit`] of its hooks, such as the
+    /// unix socket it was bound to, are then removed.
+    pub async fn join(self) -> anyhow::Result<()> {
+        let result = self.task.await.context("proxy task failed");
+        self.shutdown.remove_files().await;
+        result?
+    }
+}
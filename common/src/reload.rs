@@ -0,0 +1,169 @@
+use crate::config::{ConfigLike, Loader};
+use figment::value::{Dict, Value};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time;
+
+#[cfg(unix)]
+use {
+    anyhow::Context,
+    tokio::signal::unix::{signal, Signal as Hangup, SignalKind},
+};
+
+/// How often the configuration file is checked for changes if `watch_config` is set.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Spawns a task which reloads the configuration on SIGHUP, and when the configuration file
+/// changes if `watch_config` is set. Changes to keys in [`ConfigLike::RELOADABLE`] are picked up
+/// by whoever holds the returned receiver, while changes to any other key are logged as requiring
+/// a restart. If the reloaded configuration is invalid, the current one is kept.
+pub fn watch<'de, C>(loader: Loader<C>, config: C) -> anyhow::Result<watch::Receiver<Arc<C>>>
+where
+    C: ConfigLike<'de> + Send + Sync + 'static,
+{
+    let mut hangup = hangup()?;
+    let (sender, receiver) = watch::channel(Arc::new(config));
+
+    tokio::spawn(async move {
+        let path = loader.file().path.clone();
+        let mut last_modified = modified(&path);
+        let mut interval = time::interval(WATCH_INTERVAL);
+
+        loop {
+            let watch_config = sender.borrow().watch_config();
+            let reason = tokio::select! {
+                Some(()) = hangup.recv() => "received SIGHUP",
+                _ = interval.tick(), if watch_config => {
+                    let modified = modified(&path);
+
+                    if modified == last_modified {
+                        continue;
+                    }
+
+                    last_modified = modified;
+                    "config file changed"
+                }
+                else => break,
+            };
+
+            tracing::info!("{reason}, reloading config from {}", path.display());
+
+            let new = match loader.load().and_then(|new| new.validate().map(|()| new)) {
+                Ok(new) => new,
+                Err(error) => {
+                    tracing::error!("{error:#}");
+                    tracing::warn!("keeping the current config");
+                    continue;
+                }
+            };
+
+            let (live, restart): (Vec<_>, Vec<_>) = changed_keys(&**sender.borrow(), &new)
+                .into_iter()
+                .partition(|key| C::RELOADABLE.contains(&key.as_str()));
+
+            if live.is_empty() && restart.is_empty() {
+                tracing::info!("config is unchanged");
+                continue;
+            }
+
+            for key in restart {
+                tracing::warn!("`{key}` changed, but it only takes effect after a restart");
+            }
+
+            if live.iter().any(|key| key == "log") {
+                if let Err(error) = crate::logging::set_filter(new.log()) {
+                    tracing::error!("{error:#}");
+                }
+            }
+
+            if !live.is_empty() {
+                tracing::info!("applied changes to {}", live.join(", "));
+            }
+
+            sender.send_replace(Arc::new(new));
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// Keeps a value which is built from the configuration, such as one too costly to build every time
+/// it is needed, up to date with the configuration held by `config`. If it can't be built from a
+/// reloaded configuration, the current value is kept.
+pub fn map<C, T>(
+    mut config: watch::Receiver<Arc<C>>,
+    build: impl Fn(&C) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<watch::Receiver<Arc<T>>>
+where
+    C: Send + Sync + 'static,
+    T: Send + Sync + 'static,
+{
+    let current = Arc::clone(&config.borrow_and_update());
+    let (sender, receiver) = watch::channel(Arc::new(build(&current)?));
+
+    tokio::spawn(async move {
+        while config.changed().await.is_ok() {
+            let current = Arc::clone(&config.borrow_and_update());
+
+            match build(&current) {
+                Ok(value) => {
+                    sender.send_replace(Arc::new(value));
+                }
+                Err(error) => tracing::error!("{error:#}"),
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn changed_keys<C: Serialize>(old: &C, new: &C) -> Vec<String> {
+    fn dict(config: &impl Serialize) -> Dict {
+        Value::serialize(config)
+            .ok()
+            .and_then(Value::into_dict)
+            .unwrap_or_default()
+    }
+
+    let (old, new) = (dict(old), dict(new));
+
+    old.keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect()
+}
+
+#[cfg(unix)]
+fn hangup() -> anyhow::Result<Hangup> {
+    signal(SignalKind::hangup()).context("failed to register SIGHUP signal")
+}
+
+/// There is no SIGHUP on this platform, so the configuration is only reloaded when the file
+/// changes.
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending().await
+    }
+}
+
+#[cfg(not(unix))]
+fn hangup() -> anyhow::Result<Hangup> {
+    Ok(Hangup)
+}
@@ -0,0 +1,431 @@
+//! Rewriting of the commands apps send to Discord, such as to keep some of what they would show
+//! private. Every [`RewriteRule`] matches commands by the client id of the app, by their name and
+//! by the values at [JSON pointers](https://www.rfc-editor.org/rfc/rfc6901) into them, such as
+//! `/args/activity/details`. The commands it matches then have the values at other pointers set,
+//! filled in from a template, or stripped. Rules are applied in order, so a rule sees the command
+//! as the rules before it left it.
+
+use crate::frame::opcode;
+use crate::Frame;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    /// Only matches the commands of the app with this client id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+
+    /// Only matches commands with this name, such as `SET_ACTIVITY`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<String>,
+
+    /// Only matches commands which have these values at these pointers.
+    #[serde(default, rename = "match", skip_serializing_if = "BTreeMap::is_empty")]
+    pub matches: BTreeMap<String, Value>,
+
+    /// The values to put at these pointers, replacing whatever was there.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, Value>,
+
+    /// The strings to put at these pointers, in which `{client_id}` is replaced with the client id
+    /// of the app, and `{/some/pointer}` with the value at that pointer before the rule applied.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub template: BTreeMap<String, String>,
+
+    /// The pointers whose values are removed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub strip: Vec<String>,
+}
+
+impl RewriteRule {
+    fn pointers(&self) -> impl Iterator<Item = &String> {
+        self.matches
+            .keys()
+            .chain(self.set.keys())
+            .chain(self.template.keys())
+            .chain(&self.strip)
+    }
+
+    fn is_match(&self, client_id: Option<&str>, command: &Value) -> bool {
+        if self.client_id.is_some() && self.client_id.as_deref() != client_id {
+            return false;
+        }
+
+        if let Some(cmd) = &self.cmd {
+            if command.get("cmd").and_then(Value::as_str) != Some(cmd) {
+                return false;
+            }
+        }
+
+        self.matches
+            .iter()
+            .all(|(pointer, value)| command.pointer(pointer) == Some(value))
+    }
+
+    fn apply(&self, client_id: Option<&str>, command: &mut Value) {
+        // every template is filled in before anything changes, so that they don't depend on order
+        let templates = self
+            .template
+            .iter()
+            .map(|(pointer, template)| (pointer, fill(template, client_id, command)))
+            .collect::<Vec<_>>();
+
+        for (pointer, value) in &self.set {
+            set(command, pointer, value.clone());
+        }
+
+        for (pointer, value) in templates {
+            set(command, pointer, Value::String(value));
+        }
+
+        for pointer in &self.strip {
+            strip(command, pointer);
+        }
+    }
+}
+
+/// Checks that every pointer in `rules` is a valid JSON pointer to something other than the whole
+/// command.
+pub fn validate(rules: &[RewriteRule]) -> anyhow::Result<()> {
+    for (index, rule) in rules.iter().enumerate() {
+        if let Some(pointer) = rule.pointers().find(|pointer| !pointer.starts_with('/')) {
+            anyhow::bail!(
+                "rewrite rule {} has the path `{pointer}`, which must start with a `/`",
+                index + 1
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Rewrites the commands of a single app.
+pub struct Rewriter {
+    client_id: Option<String>,
+    rules: Arc<[RewriteRule]>,
+}
+
+impl Rewriter {
+    pub fn new(client_id: Option<String>, rules: Arc<[RewriteRule]>) -> Self {
+        Self { client_id, rules }
+    }
+
+    /// Applies every rule which matches the command carried by `frame`, if it carries one.
+    pub fn rewrite(&self, frame: &mut Frame) {
+        if frame.opcode != opcode::FRAME {
+            return;
+        }
+
+        let Ok(mut command) = serde_json::from_slice::<Value>(&frame.payload) else {
+            return;
+        };
+
+        let client_id = self.client_id.as_deref();
+        let mut rewritten = false;
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.is_match(client_id, &command) {
+                tracing::trace!("rewrite rule {} matches {frame}", index + 1);
+                rule.apply(client_id, &mut command);
+                rewritten = true;
+            }
+        }
+
+        if rewritten {
+            frame.payload = command.to_string().into_bytes();
+        }
+    }
+}
+
+/// `token` of a JSON pointer with its escapes undone.
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+/// Puts `value` at `pointer` in `target`, creating the objects on the way which don't exist yet.
+fn set(target: &mut Value, pointer: &str, value: Value) {
+    let Some((parents, last)) = pointer.rsplit_once('/') else {
+        return;
+    };
+
+    let mut current = target;
+
+    for token in parents.split('/').skip(1).map(unescape) {
+        current = match current {
+            Value::Object(object) => object
+                .entry(token)
+                .or_insert_with(|| Value::Object(Map::new())),
+            Value::Array(array) => match token.parse().ok().and_then(|i: usize| array.get_mut(i)) {
+                Some(element) => element,
+                None => return,
+            },
+            _ => return,
+        };
+    }
+
+    match current {
+        Value::Object(object) => {
+            object.insert(unescape(last), value);
+        }
+        Value::Array(array) => {
+            if let Some(element) = last.parse().ok().and_then(|i: usize| array.get_mut(i)) {
+                *element = value;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes whatever is at `pointer` in `target`.
+fn strip(target: &mut Value, pointer: &str) {
+    let Some((parent, last)) = pointer.rsplit_once('/') else {
+        return;
+    };
+
+    match target.pointer_mut(parent) {
+        Some(Value::Object(object)) => {
+            object.remove(&unescape(last));
+        }
+        Some(Value::Array(array)) => {
+            if let Some(index) = last.parse().ok().filter(|&i: &usize| i < array.len()) {
+                array.remove(index);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `template` with its placeholders replaced. Placeholders which are neither `{client_id}` nor a
+/// pointer are left as they are, while pointers to nothing are replaced with nothing.
+fn fill(template: &str, client_id: Option<&str>, command: &Value) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+
+        let placeholder = &rest[1..end];
+
+        match placeholder {
+            "client_id" => filled.push_str(client_id.unwrap_or_default()),
+            pointer if pointer.starts_with('/') => match command.pointer(pointer) {
+                Some(Value::String(value)) => filled.push_str(value),
+                Some(Value::Null) | None => {}
+                Some(value) => filled.push_str(&value.to_string()),
+            },
+            _ => filled.push_str(&rest[..=end]),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rules(rules: Value) -> Arc<[RewriteRule]> {
+        serde_json::from_value::<Vec<RewriteRule>>(rules)
+            .unwrap()
+            .into()
+    }
+
+    fn rewrite(rules: &Arc<[RewriteRule]>, client_id: &str, command: &Value) -> Value {
+        let rewriter = Rewriter::new(Some(client_id.to_owned()), Arc::clone(rules));
+        let mut frame = Frame::new(opcode::FRAME, command.to_string());
+        rewriter.rewrite(&mut frame);
+        serde_json::from_slice(&frame.payload).unwrap()
+    }
+
+    fn activity(activity: Value) -> Value {
+        json!({ "cmd": "SET_ACTIVITY", "nonce": "1", "args": { "pid": 42, "activity": activity } })
+    }
+
+    #[test]
+    fn rewrites() {
+        let cases = [
+            (
+                "set replaces values and creates the objects on the way",
+                json!([{
+                    "set": {
+                        "/args/activity/state": "Busy",
+                        "/args/activity/assets/large_image": "logo",
+                    },
+                }]),
+                activity(json!({ "state": "Editing main.rs" })),
+                activity(json!({ "state": "Busy", "assets": { "large_image": "logo" } })),
+            ),
+            (
+                "strip removes values, and ignores those which aren't there",
+                json!([{
+                    "strip": [
+                        "/args/activity/details",
+                        "/args/activity/buttons/0",
+                        "/args/activity/nope",
+                    ],
+                }]),
+                activity(json!({ "details": "secret", "buttons": ["a", "b"] })),
+                activity(json!({ "buttons": ["b"] })),
+            ),
+            (
+                "match applies the rule when every value matches",
+                json!([{
+                    "match": { "/args/activity/name": "Game", "/args/pid": 42 },
+                    "set": { "/args/activity/state": "Busy" },
+                }]),
+                activity(json!({ "name": "Game" })),
+                activity(json!({ "name": "Game", "state": "Busy" })),
+            ),
+            (
+                "match skips the rule when any value differs",
+                json!([{
+                    "match": { "/args/activity/name": "Game", "/args/pid": 7 },
+                    "set": { "/args/activity/state": "Busy" },
+                }]),
+                activity(json!({ "name": "Game" })),
+                activity(json!({ "name": "Game" })),
+            ),
+            (
+                "cmd skips the rule for other commands",
+                json!([{ "cmd": "SUBSCRIBE", "set": { "/args/activity/state": "Busy" } }]),
+                activity(json!({})),
+                activity(json!({})),
+            ),
+            (
+                "client_id applies the rule to the right app",
+                json!([{ "client_id": "123", "set": { "/args/activity/state": "Busy" } }]),
+                activity(json!({})),
+                activity(json!({ "state": "Busy" })),
+            ),
+            (
+                "client_id skips the rule for other apps",
+                json!([{ "client_id": "456", "set": { "/args/activity/state": "Busy" } }]),
+                activity(json!({})),
+                activity(json!({})),
+            ),
+            (
+                "templates are filled in",
+                json!([{
+                    "template": {
+                        "/args/activity/details":
+                            "{client_id} plays {/args/activity/name} as {/args/pid}{/args/nope}",
+                    },
+                }]),
+                activity(json!({ "name": "Game" })),
+                activity(json!({ "name": "Game", "details": "123 plays Game as 42" })),
+            ),
+            (
+                "unknown and unclosed placeholders are left as they are",
+                json!([{ "template": { "/args/activity/details": "{nope} {/args/pid" } }]),
+                activity(json!({})),
+                activity(json!({ "details": "{nope} {/args/pid" })),
+            ),
+            (
+                "templates see the command from before the rule",
+                json!([{
+                    "set": { "/args/activity/name": "Something" },
+                    "template": { "/args/activity/details": "was {/args/activity/name}" },
+                }]),
+                activity(json!({ "name": "Game" })),
+                activity(json!({ "name": "Something", "details": "was Game" })),
+            ),
+            (
+                "rules see the command as the rules before them left it",
+                json!([
+                    { "set": { "/args/activity/state": "Busy" } },
+                    {
+                        "match": { "/args/activity/state": "Busy" },
+                        "strip": ["/args/activity/details"],
+                    },
+                ]),
+                activity(json!({ "details": "secret" })),
+                activity(json!({ "state": "Busy" })),
+            ),
+            (
+                "rules don't see what the rules after them do",
+                json!([
+                    {
+                        "match": { "/args/activity/state": "Busy" },
+                        "strip": ["/args/activity/details"],
+                    },
+                    { "set": { "/args/activity/state": "Busy" } },
+                ]),
+                activity(json!({ "details": "secret" })),
+                activity(json!({ "details": "secret", "state": "Busy" })),
+            ),
+            (
+                "escaped pointers refer to keys with `/` and `~` in them",
+                json!([{ "set": { "/args/a~1b": 1, "/args/c~0d": 2 } }]),
+                activity(json!({})),
+                json!({
+                    "cmd": "SET_ACTIVITY",
+                    "nonce": "1",
+                    "args": { "pid": 42, "activity": {}, "a/b": 1, "c~d": 2 },
+                }),
+            ),
+        ];
+
+        for (name, rewrite_rules, command, expected) in cases {
+            assert_eq!(
+                rewrite(&rules(rewrite_rules), "123", &command),
+                expected,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_rewrites_commands() {
+        let rewriter = Rewriter::new(
+            None,
+            rules(json!([{ "set": { "/args/activity/state": "Busy" } }])),
+        );
+
+        for mut frame in [
+            Frame::new(
+                opcode::HANDSHAKE,
+                json!({ "v": 1, "client_id": "123" }).to_string(),
+            ),
+            Frame::new(opcode::FRAME, "not json"),
+        ] {
+            let original = frame.clone();
+            rewriter.rewrite(&mut frame);
+            assert_eq!(frame, original);
+        }
+    }
+
+    #[test]
+    fn validates_pointers() {
+        assert!(validate(&rules(json!([
+            { "match": { "/cmd": "SET_ACTIVITY" }, "set": { "/args/a": 1 } },
+            { "template": { "/args/b": "" }, "strip": ["/args/c"] },
+        ])))
+        .is_ok());
+
+        for (invalid, index) in [
+            (json!([{ "match": { "cmd": "SET_ACTIVITY" } }]), 1),
+            (json!([{}, { "set": { "args/a": 1 } }]), 2),
+            (json!([{}, {}, { "template": { "": "" } }]), 3),
+            (json!([{ "strip": ["/args", "args"] }]), 1),
+        ] {
+            let error = validate(&rules(invalid)).unwrap_err().to_string();
+            assert!(
+                error.starts_with(&format!("rewrite rule {index} ")),
+                "{error}"
+            );
+        }
+    }
+}
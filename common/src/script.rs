@@ -0,0 +1,318 @@
+//! Scripts which can inspect and change the frames of every session, for the tweaks which are too
+//! specific for the configuration. They are written in [Rhai](https://rhai.rs), and may define any
+//! of these functions:
+//!
+//! - `on_handshake(frame)`, called with the handshake of the app.
+//! - `on_frame(direction, frame)`, called with every frame after it, where `direction` is
+//!   `"outgoing"` for frames on their way to Discord and `"incoming"` for frames on their way to
+//!   the app.
+//! - `on_close()`, called once when a session which reached Discord ends, however it ends.
+//!
+//! A frame is a map of its `opcode` and its `data`, which is its payload parsed as JSON, or its
+//! `payload` as a string if it isn't JSON. Each function returns what to do with the frame it was
+//! given: nothing or `true` passes it on as it is, `false` drops it, a frame passes that frame on
+//! in its place, and an array of frames passes every one of them on. Frames which have `reply` set
+//! to `true` are sent back to where the frame came from instead. `on_close` returns the frames to
+//! send to Discord before the session closes, such as to clear the activity, and its replies are
+//! sent to the app if it is still connected. Within a session, `this` is a map which is kept
+//! between calls, and starts out with the `client_id` of the app.
+//!
+//! Scripts only get to touch the frames they are given, as they can't import modules or evaluate
+//! code, and every call is stopped once it runs for longer than its time budget. A call which
+//! fails in any way passes its frame on as it is.
+
+use crate::frame::Direction;
+use crate::Frame;
+use anyhow::Context;
+use fs_err as fs;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+use serde_json::Value;
+use std::cell::Cell;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_BUDGET: Duration = Duration::from_millis(50);
+
+/// The functions a script may define.
+const HOOKS: [&str; 3] = ["on_handshake", "on_frame", "on_close"];
+
+/// Frames are far smaller than this, so a script which builds anything larger is runaway.
+const MAX_SIZE: usize = 1024 * 1024;
+
+/// How many elements an array or map built by a script may have.
+const MAX_ELEMENTS: usize = 64 * 1024;
+
+/// How deep functions may call into each other.
+const MAX_CALL_LEVELS: usize = 64;
+
+thread_local! {
+    /// When the call running on this thread runs out of its budget. Calls never yield, so they
+    /// can't move to another thread halfway through.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// What a script did with a frame.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// The frames to pass on.
+    pub forward: Vec<Frame>,
+
+    /// The frames to send back to where the frame came from.
+    pub reply: Vec<Frame>,
+}
+
+impl Outcome {
+    fn pass(frame: Frame) -> Self {
+        Self {
+            forward: vec![frame],
+            reply: Vec::new(),
+        }
+    }
+}
+
+/// A compiled script, shared by every session.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    budget: Duration,
+    name: String,
+
+    /// Which of [`HOOKS`] the script defines.
+    defined: Vec<&'static str>,
+}
+
+impl Script {
+    /// Compiles the script at `path`, whose calls may each run for `budget`.
+    pub fn load(path: &Path, budget: Duration) -> anyhow::Result<Self> {
+        let source = fs::read_to_string(path)?;
+        let mut engine = Engine::new();
+
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_string_size(MAX_SIZE)
+            .set_max_array_size(MAX_ELEMENTS)
+            .set_max_map_size(MAX_ELEMENTS)
+            .on_print(|text| tracing::info!("script: {text}"))
+            .on_debug(|text, _, position| tracing::debug!("script at {position}: {text}"))
+            .on_progress(|_| {
+                DEADLINE
+                    .get()
+                    .filter(|&deadline| Instant::now() >= deadline)
+                    .map(|_| Dynamic::UNIT)
+            })
+            .disable_symbol("eval");
+
+        let ast = engine
+            .compile(source)
+            .with_context(|| format!("failed to compile script {}", path.display()))?;
+        let defined = HOOKS
+            .into_iter()
+            .filter(|&hook| ast.iter_functions().any(|function| function.name == hook))
+            .collect::<Vec<_>>();
+
+        anyhow::ensure!(
+            !defined.is_empty(),
+            "script {} defines none of {}",
+            path.display(),
+            HOOKS.join(", ")
+        );
+
+        Ok(Self {
+            engine,
+            ast,
+            budget,
+            name: path.display().to_string(),
+            defined,
+        })
+    }
+}
+
+/// A script running for a single session, along with what it keeps in `this`.
+pub struct ScriptSession {
+    script: Arc<Script>,
+    this: Mutex<Dynamic>,
+}
+
+impl ScriptSession {
+    pub fn new(script: Arc<Script>, client_id: Option<String>) -> Self {
+        let mut this = Map::new();
+        this.insert(
+            "client_id".into(),
+            client_id.map_or(Dynamic::UNIT, Dynamic::from),
+        );
+
+        Self {
+            script,
+            this: Mutex::new(this.into()),
+        }
+    }
+
+    pub fn on_handshake(&self, frame: Frame) -> Outcome {
+        self.run("on_handshake", frame, None)
+    }
+
+    pub fn on_frame(&self, direction: Direction, frame: Frame) -> Outcome {
+        self.run("on_frame", frame, Some(direction))
+    }
+
+    /// Called once the session ends, returning the frames to send before it closes.
+    pub fn on_close(&self) -> Outcome {
+        const HOOK: &str = "on_close";
+
+        if !self.script.defined.contains(&HOOK) {
+            return Outcome::default();
+        }
+
+        let result = self.call(HOOK, ()).and_then(|result| {
+            if result.is_unit() {
+                Ok(Outcome::default())
+            } else {
+                frames(result)
+            }
+        });
+
+        result.unwrap_or_else(|error| {
+            tracing::warn!("{HOOK} of script {} failed: {error:#}", self.script.name);
+            Outcome::default()
+        })
+    }
+
+    fn run(&self, hook: &str, frame: Frame, direction: Option<Direction>) -> Outcome {
+        if !self.script.defined.contains(&hook) {
+            return Outcome::pass(frame);
+        }
+
+        let argument = to_dynamic(&frame);
+        let result = match direction {
+            Some(direction) => self.call(hook, (direction.as_str().to_owned(), argument)),
+            None => self.call(hook, (argument,)),
+        };
+
+        match result.and_then(|result| outcome(&frame, result)) {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                tracing::warn!(
+                    "{hook} of script {} failed, passing the frame on as it is: {error:#}",
+                    self.script.name
+                );
+                Outcome::pass(frame)
+            }
+        }
+    }
+
+    fn call(&self, hook: &str, arguments: impl FuncArgs) -> anyhow::Result<Dynamic> {
+        let script = &*self.script;
+        let mut this = self.this.lock().unwrap();
+
+        DEADLINE.set(Some(Instant::now() + script.budget));
+        let result = script.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(&mut this),
+            &mut Scope::new(),
+            &script.ast,
+            hook,
+            arguments,
+        );
+        DEADLINE.set(None);
+
+        result.map_err(|error| match *error {
+            EvalAltResult::ErrorTerminated(..) => anyhow::anyhow!(
+                "ran for longer than its budget of {} ms",
+                script.budget.as_millis()
+            ),
+            error => anyhow::Error::new(error),
+        })
+    }
+}
+
+/// `frame` as scripts see it.
+fn to_dynamic(frame: &Frame) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("opcode".into(), Dynamic::from_int(frame.opcode.into()));
+
+    let data = serde_json::from_slice::<Value>(&frame.payload)
+        .ok()
+        .and_then(|data| rhai::serde::to_dynamic(data).ok());
+
+    match data {
+        Some(data) => map.insert("data".into(), data),
+        None => map.insert(
+            "payload".into(),
+            String::from_utf8_lossy(&frame.payload).into_owned().into(),
+        ),
+    };
+
+    map.into()
+}
+
+/// The frame which scripts see as `value`, along with whether it is a reply.
+fn from_dynamic(value: Dynamic) -> anyhow::Result<(Frame, bool)> {
+    let kind = value.type_name();
+    let map = value
+        .try_cast::<Map>()
+        .with_context(|| format!("a frame must be a map, not {kind}"))?;
+
+    let opcode = map
+        .get("opcode")
+        .and_then(|opcode| opcode.as_int().ok())
+        .and_then(|opcode| u32::try_from(opcode).ok())
+        .context("a frame must have an `opcode` which is a positive integer")?;
+
+    let payload = match (map.get("data"), map.get("payload")) {
+        (Some(data), _) => serde_json::to_vec(&rhai::serde::from_dynamic::<Value>(data)?)
+            .context("failed to serialize the `data` of a frame")?,
+        (None, Some(payload)) => payload
+            .clone()
+            .into_string()
+            .map_err(|kind| {
+                anyhow::anyhow!("the `payload` of a frame must be a string, not {kind}")
+            })?
+            .into_bytes(),
+        (None, None) => Vec::new(),
+    };
+
+    let reply = map
+        .get("reply")
+        .and_then(|reply| reply.as_bool().ok())
+        .unwrap_or(false);
+
+    Ok((Frame::new(opcode, payload), reply))
+}
+
+/// What to do with `frame`, going by what a script returned for it.
+fn outcome(frame: &Frame, result: Dynamic) -> anyhow::Result<Outcome> {
+    if result.is_unit() || result.as_bool() == Ok(true) {
+        Ok(Outcome::pass(frame.clone()))
+    } else if result.as_bool() == Ok(false) {
+        Ok(Outcome::default())
+    } else {
+        frames(result)
+    }
+}
+
+/// The frames a script returned, which is either a frame or an array of them.
+fn frames(result: Dynamic) -> anyhow::Result<Outcome> {
+    let frames = if result.is_array() {
+        result.cast::<Array>()
+    } else {
+        vec![result]
+    };
+
+    let mut outcome = Outcome::default();
+
+    for frame in frames {
+        let (frame, reply) = from_dynamic(frame)?;
+
+        if reply {
+            outcome.reply.push(frame);
+        } else {
+            outcome.forward.push(frame);
+        }
+    }
+
+    Ok(outcome)
+}
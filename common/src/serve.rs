@@ -1,9 +1,9 @@
 #[cfg(unix)]
 mod unix {
-    use crate::serve::{ServableListener, ServableStream};
+    use crate::serve::{ServableListener, ServableStream, SplittableStream};
     use async_trait::async_trait;
     use std::io;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
     use tokio::net::unix::SocketAddr;
     use tokio::net::{unix, UnixListener, UnixStream};
     use unix::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
@@ -20,19 +20,18 @@ mod unix {
         async fn accept(&self) -> io::Result<(Self::Stream, Self::SocketAddr)> {
             UnixListener::accept(self).await
         }
+
+        fn bound_file(socket: &S) -> Option<PathBuf> {
+            Some(socket.as_ref().to_path_buf())
+        }
     }
 
-    #[async_trait]
-    impl<S: AsRef<Path> + Send + 'static> ServableStream<S> for UnixStream {
+    impl SplittableStream for UnixStream {
         type OwnedReadHalf = OwnedReadHalf;
         type OwnedWriteHalf = OwnedWriteHalf;
         type ReadHalf<'a> = ReadHalf<'a> where Self: 'a;
         type WriteHalf<'a> = WriteHalf<'a> where Self: 'a;
 
-        async fn connect(socket: S) -> io::Result<Self> {
-            UnixStream::connect(socket).await
-        }
-
         fn into_split(self) -> (Self::OwnedReadHalf, Self::OwnedWriteHalf) {
             UnixStream::into_split(self)
         }
@@ -41,27 +40,66 @@ mod unix {
             UnixStream::split(self)
         }
     }
+
+    #[async_trait]
+    impl<S: AsRef<Path> + Send + 'static> ServableStream<S> for UnixStream {
+        async fn connect(socket: S) -> io::Result<Self> {
+            UnixStream::connect(socket).await
+        }
+    }
 }
 
-use crate::{ReadFrom, WriteTo};
+use crate::access::AccessList;
+use crate::activity::{self, ActivityFilter, Answers};
+use crate::frame::{close_code, opcode, Direction};
+use crate::link::{self, LinkConfig, LinkOptions};
+use crate::offline::{self, Answer, Emulator};
+use crate::preamble::{self, Hello, Negotiated};
+use crate::rewrite::{RewriteRule, Rewriter};
+use crate::script::{Script, ScriptSession};
+use crate::shutdown::{Shutdown, SHUTDOWN_TIMEOUT};
+use crate::{Frame, ReadFrom, WriteTo};
 use anyhow::Context;
 use async_trait::async_trait;
-use std::fmt::{Debug, Display};
+use clap::ValueEnum;
+use futures_util::future::{self, OptionFuture};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::any::{Any, TypeId};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Debug, Display};
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::ControlFlow;
 use std::path::Display as DisplayablePath;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::{tcp, TcpListener, TcpStream, ToSocketAddrs};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{self, MissedTickBehavior};
 
 #[async_trait]
 pub trait ServableListener<S: Send + 'static>: Sized {
-    type Stream: ServableStream<S>;
+    type Stream: SplittableStream;
     type SocketAddr;
 
     async fn bind(socket: S) -> io::Result<Self>;
     async fn accept(&self) -> io::Result<(Self::Stream, Self::SocketAddr)>;
+
+    /// The ip address of the peer at `address`, if it has one.
+    fn peer_ip(_address: &Self::SocketAddr) -> Option<IpAddr> {
+        None
+    }
+
+    /// The file created by binding to `socket`, if any, which is left behind once the listener is
+    /// dropped.
+    fn bound_file(_socket: &S) -> Option<PathBuf> {
+        None
+    }
 }
 
 #[async_trait]
@@ -76,10 +114,76 @@ impl<S: ToSocketAddrs + Send + 'static> ServableListener<S> for TcpListener {
     async fn accept(&self) -> io::Result<(Self::Stream, Self::SocketAddr)> {
         TcpListener::accept(self).await
     }
+
+    fn peer_ip(address: &Self::SocketAddr) -> Option<IpAddr> {
+        Some(address.ip().to_canonical())
+    }
+}
+
+/// Several TCP listeners which are accepted from as if they were a single listener.
+///
+/// IPv6 listeners only accept IPv6 connections, so that a listener on `0.0.0.0` and a listener on
+/// `[::]` can both be bound to the same port on dual-stack systems.
+pub struct TcpListeners(Vec<TcpListener>);
+
+impl TcpListeners {
+    fn bind_one(address: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+
+        if address.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+
+        socket.set_nonblocking(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1024)?;
+
+        TcpListener::from_std(socket.into())
+    }
 }
 
 #[async_trait]
-pub trait ServableStream<S: Send + 'static>: Sized {
+impl ServableListener<SocketAddrs> for TcpListeners {
+    type Stream = TcpStream;
+    type SocketAddr = SocketAddr;
+
+    async fn bind(socket: SocketAddrs) -> io::Result<Self> {
+        socket
+            .0
+            .into_iter()
+            .map(|address| {
+                Self::bind_one(address).map_err(|error| {
+                    io::Error::new(
+                        error.kind(),
+                        format!("failed to bind to {address}: {error}"),
+                    )
+                })
+            })
+            .collect::<io::Result<_>>()
+            .map(Self)
+    }
+
+    async fn accept(&self) -> io::Result<(Self::Stream, Self::SocketAddr)> {
+        let (result, _, _) =
+            future::select_all(self.0.iter().map(|listener| Box::pin(listener.accept()))).await;
+
+        result
+    }
+
+    fn peer_ip(address: &Self::SocketAddr) -> Option<IpAddr> {
+        Some(address.ip().to_canonical())
+    }
+}
+
+/// A stream which can be split into halves to read from and write to.
+pub trait SplittableStream: Sized {
     type OwnedReadHalf: ReadFrom + Send + 'static;
     type OwnedWriteHalf: WriteTo + Send + 'static;
     type ReadHalf<'a>: ReadFrom + Send
@@ -89,22 +193,26 @@ pub trait ServableStream<S: Send + 'static>: Sized {
     where
         Self: 'a;
 
-    async fn connect(socket: S) -> io::Result<Self>;
     fn into_split(self) -> (Self::OwnedReadHalf, Self::OwnedWriteHalf);
     fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>);
+
+    /// Applies `options` to this stream, if they are relevant to it.
+    fn configure(&self, _options: &LinkOptions) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait]
-impl<S: ToSocketAddrs + Send + 'static> ServableStream<S> for TcpStream {
+pub trait ServableStream<S: Send + 'static>: SplittableStream {
+    async fn connect(socket: S) -> io::Result<Self>;
+}
+
+impl SplittableStream for TcpStream {
     type OwnedReadHalf = tcp::OwnedReadHalf;
     type OwnedWriteHalf = tcp::OwnedWriteHalf;
     type ReadHalf<'a> = tcp::ReadHalf<'a> where Self: 'a;
     type WriteHalf<'a> = tcp::WriteHalf<'a> where Self: 'a;
 
-    async fn connect(socket: S) -> io::Result<Self> {
-        TcpStream::connect(socket).await
-    }
-
     fn into_split(self) -> (Self::OwnedReadHalf, Self::OwnedWriteHalf) {
         TcpStream::into_split(self)
     }
@@ -112,6 +220,17 @@ impl<S: ToSocketAddrs + Send + 'static> ServableStream<S> for TcpStream {
     fn split(&'_ mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
         TcpStream::split(self)
     }
+
+    fn configure(&self, options: &LinkOptions) -> io::Result<()> {
+        options.apply(self)
+    }
+}
+
+#[async_trait]
+impl<S: ToSocketAddrs + Send + 'static> ServableStream<S> for TcpStream {
+    async fn connect(socket: S) -> io::Result<Self> {
+        TcpStream::connect(socket).await
+    }
 }
 
 pub trait Displayable {
@@ -145,137 +264,1699 @@ macro_rules! impl_Displayable {
 
 impl_Displayable!(SocketAddr);
 
-pub type OnStreamConnectFail = Box<dyn FnMut(&io::Error)>;
+/// A host name or ip address along with a port. As it is resolved every time it's connected to,
+/// changes to the addresses a host name resolves to are picked up on the next connection.
+impl Displayable for (String, u16) {
+    type Display<'d> = HostAndPort<'d>;
 
-#[derive(Default)]
-pub struct ServeHooks {
-    pub on_stream_connect_fail: Option<OnStreamConnectFail>,
+    fn display(&self) -> Self::Display<'_> {
+        HostAndPort(&self.0, self.1)
+    }
 }
 
-impl ServeHooks {
-    pub fn on_stream_connect_fail(mut self, run: impl FnMut(&io::Error) + 'static) -> Self {
-        self.on_stream_connect_fail = Some(Box::new(run));
-        self
+pub struct HostAndPort<'a>(pub &'a str, pub u16);
+
+impl Display for HostAndPort<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.contains(':') {
+            write!(f, "[{}]:{}", self.0, self.1)
+        } else {
+            write!(f, "{}:{}", self.0, self.1)
+        }
     }
 }
 
-#[tracing::instrument(skip_all)]
-async fn read_then_send_worker<R, W>(
-    read_from: &R,
-    write_to: &W,
-    read_from_name: &str,
-    write_to_name: &str,
-    finished_receiver: mpsc::Sender<()>,
-) where
-    R: ReadFrom,
-    W: WriteTo,
-{
-    tracing::trace!("{read_from_name}->{write_to_name} worker started");
-
-    loop {
-        match crate::read_from_then_write_to(read_from, write_to, read_from_name, write_to_name)
-            .await
-        {
-            ControlFlow::Continue(()) => continue,
-            ControlFlow::Break(result) => {
-                match result {
-                    Ok(()) => tracing::debug!(
-                        "finished reading from {read_from_name} and sending to {write_to_name}"
-                    ),
-                    Err(error) => tracing::error!("{error:#}"),
-                }
+/// A list of socket addresses, for binding [`TcpListeners`].
+#[derive(Clone, Debug)]
+pub struct SocketAddrs(pub Vec<SocketAddr>);
 
-                finished_receiver.send(()).await.unwrap();
-                break;
+impl Display for SocketAddrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, address) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
             }
+
+            write!(f, "{address}")?;
         }
+
+        Ok(())
     }
 }
 
-pub async fn serve<L, S, LS, SS>(
-    listener_bind_to: LS,
-    stream_connect_to: SS,
-    new_client_name: &'static str,
-    stream_name: &'static str,
-    mut hooks: ServeHooks,
-) -> anyhow::Result<()>
-where
-    LS: Displayable + Send + 'static,
-    L: ServableListener<LS>,
-    L::SocketAddr: Debug,
-    SS: Displayable + Clone + Send + 'static,
-    S: ServableStream<SS>,
-{
-    tracing::debug!("start serving connections");
-    let error_message = format!("failed to bind to {}", listener_bind_to.display());
-    let listener = L::bind(listener_bind_to).await.context(error_message)?;
+impl_Displayable!(SocketAddrs);
 
-    loop {
-        let (stream, addr) = listener
-            .accept()
-            .await
-            .context("failed to accept new connection")?;
-        let (new_client_read_half, new_client_write_half) = stream.into_split();
-        tracing::info!(?addr, "new connection from {new_client_name} incoming");
+/// How long a new client is given to send its handshake, and how long a candidate is given to
+/// answer it before the next one is tried.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-        tracing::debug!("creating new connection to {stream_name}");
-        let error_message = format!("failed to connect to {}", stream_connect_to.display());
-        let stream = match S::connect(stream_connect_to.clone()).await {
-            Ok(stream) => stream,
-            Err(error) => {
-                if let Some(mut hook) = hooks.on_stream_connect_fail.take() {
-                    hook(&error)
+/// How long connecting to a candidate may take before the next one is tried. Candidates on the LAN
+/// answer well within this, so running out of it means the candidate is asleep or gone, which would
+/// otherwise only be noticed once the operating system gives up on connecting minutes later.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How many frames may be queued up to be sent to either side of a session.
+const FRAME_BUFFER: usize = 16;
+
+/// How long frames which were already read are given to be written once a session is torn down.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait before accepting again after failing to accept a connection, as the cause of
+/// that (such as running out of file descriptors) usually persists for a moment.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How many connections over the session limits may be waiting at once to be told so. Any more are
+/// closed straight away, so that rejected connections can't pile up either.
+const MAX_PENDING_REJECTIONS: usize = 16;
+
+/// Clocks which are off by less than this many milliseconds are close enough, as the measurement
+/// isn't any more accurate than the latency of the link anyway.
+const CLOCK_SKEW_THRESHOLD: i64 = 1000;
+
+/// The kinds of errors which can happen while serving. Each of them only ends the session it
+/// happened in, unless it is made fatal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Failing to accept a new connection.
+    Accept,
+
+    /// Failing to connect a session to any of the addresses to connect to.
+    Connect,
+}
+
+/// What middleware knows about the session it is called for.
+pub struct SessionContext {
+    /// Unique among the sessions of a single [`serve`].
+    pub id: u64,
+
+    /// The ip address of the new client, if it has one.
+    pub peer_ip: Option<IpAddr>,
+
+    pub new_client_name: &'static str,
+    pub stream_name: &'static str,
+
+    /// The client id of the app, once its handshake was read.
+    pub client_id: Option<String>,
+
+    /// When the new client was accepted.
+    pub accepted: Instant,
+
+    /// When the session was connected to the stream, if it was.
+    pub connected: Option<Instant>,
+
+    /// Values which middleware keeps for the session, one of each type.
+    extensions: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl SessionContext {
+    fn new(
+        id: u64,
+        peer_ip: Option<IpAddr>,
+        new_client_name: &'static str,
+        stream_name: &'static str,
+    ) -> Self {
+        Self {
+            id,
+            peer_ip,
+            new_client_name,
+            stream_name,
+            client_id: None,
+            accepted: Instant::now(),
+            connected: None,
+            extensions: HashMap::new(),
+        }
+    }
+
+    /// Keeps `value` for the session, returning the value of the same type which was kept before.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.extensions
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.extensions
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+}
+
+/// Code which runs at each step of every session, such as to filter, log, or measure them. Any
+/// number of middleware can be stacked with [`ServeHooks::middleware`]. Outgoing frames go through
+/// them in the order they were added, and incoming frames in the opposite order.
+pub trait Middleware: Send + Sync + 'static {
+    /// Called when a new client was accepted, before anything was read from it. Breaking rejects
+    /// the session, whose handshake is then answered with the given Close frame.
+    fn on_accept(&self, _context: &mut SessionContext) -> ControlFlow<Frame> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called once the session is connected to the stream, and frames start to flow.
+    fn on_connect(&self, _context: &mut SessionContext) {}
+
+    /// Called with every frame of the session, including the handshake, which is passed on as the
+    /// returned frame, or dropped if `None` is returned.
+    fn on_frame(
+        &self,
+        _context: &mut SessionContext,
+        _direction: Direction,
+        frame: Frame,
+    ) -> Option<Frame> {
+        Some(frame)
+    }
+
+    /// Called once the session is over, however it ended, if [`Middleware::on_accept`] was called
+    /// for it.
+    fn on_close(&self, _context: &mut SessionContext) {}
+}
+
+/// Runs `frame` through every one of `middleware`, unless one of them drops it.
+fn through_middleware(
+    middleware: &[Arc<dyn Middleware>],
+    context: &Mutex<SessionContext>,
+    direction: Direction,
+    frame: Frame,
+) -> Option<Frame> {
+    if middleware.is_empty() {
+        return Some(frame);
+    }
+
+    let mut context = context.lock().unwrap();
+    middleware.iter().try_fold(frame, |frame, middleware| {
+        middleware.on_frame(&mut context, direction, frame)
+    })
+}
+
+/// What a session looked like at some point, as returned by [`Sessions::list`].
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub peer_ip: Option<IpAddr>,
+    pub client_id: Option<String>,
+    pub accepted: Instant,
+    pub connected: Option<Instant>,
+}
+
+/// The sessions being served, which can be looked at from outside of [`serve`]. Clones of it share
+/// the same sessions.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<BTreeMap<u64, Arc<Mutex<SessionContext>>>>>);
+
+impl Sessions {
+    /// Every session being served, in the order they were accepted.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .map(|context| {
+                let context = context.lock().unwrap_or_else(|error| error.into_inner());
+
+                SessionInfo {
+                    id: context.id,
+                    peer_ip: context.peer_ip,
+                    client_id: context.client_id.clone(),
+                    accepted: context.accepted,
+                    connected: context.connected,
                 }
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Keeps a session in [`Sessions`] until it is dropped, when [`Middleware::on_close`] of the
+/// middleware which accepted the session is called as well, so that this happens even if the
+/// session was aborted.
+struct Closing {
+    middleware: Vec<Arc<dyn Middleware>>,
+    context: Arc<Mutex<SessionContext>>,
+    sessions: Sessions,
+}
+
+impl Closing {
+    fn new(context: &Arc<Mutex<SessionContext>>, sessions: &Sessions) -> Self {
+        let id = context.lock().unwrap().id;
+        sessions.0.lock().unwrap().insert(id, Arc::clone(context));
+
+        Self {
+            middleware: Vec::new(),
+            context: Arc::clone(context),
+            sessions: sessions.clone(),
+        }
+    }
+
+    /// Calls [`Middleware::on_accept`] of every one of `middleware` until one of them rejects the
+    /// session, returning the Close frame it was rejected with.
+    fn accept(&mut self, middleware: &[Arc<dyn Middleware>]) -> Option<Frame> {
+        let mut context = self.context.lock().unwrap();
+
+        for middleware in middleware {
+            self.middleware.push(Arc::clone(middleware));
 
-                Err(error).context(error_message)?
+            if let ControlFlow::Break(close) = middleware.on_accept(&mut context) {
+                return Some(close);
             }
+        }
+
+        None
+    }
+}
+
+impl Drop for Closing {
+    fn drop(&mut self) {
+        let id = {
+            let mut context = self
+                .context
+                .lock()
+                .unwrap_or_else(|error| error.into_inner());
+
+            for middleware in &self.middleware {
+                middleware.on_close(&mut context);
+            }
+
+            context.id
         };
 
-        let (stream_read_half, stream_write_half) = stream.into_split();
-        let (nc2s_finished_sender, mut nc2s_finished_receiver) = mpsc::channel(1);
-        let (s2nc_finished_sender, mut s2nc_finished_receiver) = mpsc::channel(1);
+        self.sessions
+            .0
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .remove(&id);
+    }
+}
 
-        tracing::debug!("created new connection to {stream_name}");
+pub type OnStreamConnectFail = Box<dyn Fn(&anyhow::Error) + Send + Sync>;
+pub type GetSessionLimits = Box<dyn Fn() -> SessionLimits + Send + Sync>;
+pub type GetAccessList = Box<dyn Fn() -> AccessList + Send + Sync>;
+pub type GetSessionPolicy = Box<dyn Fn() -> Arc<SessionPolicy> + Send + Sync>;
 
-        // read from new client, then send to stream
-        tokio::spawn(async move {
-            read_then_send_worker(
-                &new_client_read_half,
-                &stream_write_half,
-                new_client_name,
-                stream_name,
-                nc2s_finished_sender,
-            )
-            .await
-        });
+/// How many sessions may be served at once, in total and for each peer ip address. There is no
+/// limit on either if it isn't set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SessionLimits {
+    pub total: Option<usize>,
+    pub per_ip: Option<usize>,
+}
 
-        // read from stream, then send to new client
-        tokio::spawn(async move {
-            read_then_send_worker(
-                &stream_read_half,
-                &new_client_write_half,
-                stream_name,
-                new_client_name,
-                s2nc_finished_sender,
-            )
-            .await
-        });
+/// How a session is served. It is looked up once for every new session, so a session keeps being
+/// served the way it started even if this changes while serving.
+#[derive(Clone, Default)]
+pub struct SessionPolicy {
+    /// The options of whichever side of a session is the link between host and remote.
+    pub link_options: LinkOptions,
 
-        tokio::spawn(async move {
-            let mut nc2s_finished = false;
-            let mut s2nc_finished = false;
+    /// Whether to answer new clients in place of the stream while it is unavailable, rather than
+    /// closing their session.
+    pub offline: bool,
 
-            loop {
-                tokio::select! {
-                    Some(()) = nc2s_finished_receiver.recv() => nc2s_finished = true,
-                    Some(()) = s2nc_finished_receiver.recv() => s2nc_finished = true,
-                }
+    /// How long to hold back the activity updates of new clients after one was forwarded, or
+    /// `None` to forward every update straight away.
+    pub coalesce_activity: Option<Duration>,
 
-                if nc2s_finished && s2nc_finished {
-                    tracing::info!("connection to {stream_name} closed");
-                    break;
-                }
-            }
-        });
+    /// Whether to skip the activity updates of new clients which are the same as the last one.
+    pub dedup_activity: bool,
+
+    /// Whether to shift the timestamps of the activities set by new clients by how far their
+    /// clock is off from ours, as measured during the preamble.
+    pub correct_clock_skew: bool,
+
+    /// The rules to rewrite the commands of new clients with.
+    pub rewrite_rules: Arc<[RewriteRule]>,
+
+    /// The script to run on the frames of every session.
+    pub script: Option<Arc<Script>>,
+}
+
+impl SessionPolicy {
+    /// Everything in `config` which is about sessions, compiling its script if it has one.
+    pub fn link_config(config: &LinkConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            link_options: config.options(),
+            coalesce_activity: config.coalesce_activity(),
+            dedup_activity: config.dedup_activity.unwrap_or(true),
+            rewrite_rules: config.rewrite.iter().cloned().collect(),
+            script: config.script()?.map(Arc::new),
+            ..Self::default()
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct ServeHooks {
+    pub on_stream_connect_fail: Option<OnStreamConnectFail>,
+
+    /// The hello to answer the preamble of every new client with.
+    pub new_client_preamble: Option<Hello>,
+
+    /// The hello to start a preamble with on every connection to the stream.
+    pub stream_preamble: Option<Hello>,
+
+    /// The Close frame to answer the handshake of a new client with when the stream is
+    /// unavailable.
+    pub unavailable: Option<Frame>,
+
+    /// The kinds of errors which stop serving altogether.
+    pub fatal_errors: Vec<ErrorKind>,
+
+    /// Stops serving once a shutdown is requested.
+    pub shutdown: Shutdown,
+
+    /// How many sessions may be served at once, which is looked up for every new connection so
+    /// that it can change while serving. Any more are answered with a Close frame.
+    pub session_limits: Option<GetSessionLimits>,
+
+    /// Which peers may connect, which is looked up for every new connection so that it can change
+    /// while serving. Connections from any other peer are closed straight away.
+    pub access_list: Option<GetAccessList>,
+
+    /// How every session is served, unless it is looked up with `live_policy`.
+    pub policy: SessionPolicy,
+
+    /// How every session is served, which is looked up for every new session so that it can change
+    /// while serving.
+    pub live_policy: Option<GetSessionPolicy>,
+
+    /// The middleware of every session, in the order outgoing frames go through them.
+    pub middleware: Vec<Arc<dyn Middleware>>,
+
+    /// Where the sessions being served are kept track of.
+    pub sessions: Sessions,
+}
+
+impl ServeHooks {
+    pub fn on_stream_connect_fail(
+        mut self,
+        run: impl Fn(&anyhow::Error) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_stream_connect_fail = Some(Box::new(run));
+        self
+    }
+
+    pub fn new_client_preamble(mut self, hello: Hello) -> Self {
+        self.new_client_preamble = Some(hello);
+        self
+    }
+
+    pub fn stream_preamble(mut self, hello: Hello) -> Self {
+        self.stream_preamble = Some(hello);
+        self
+    }
+
+    pub fn link_options(mut self, options: LinkOptions) -> Self {
+        self.policy.link_options = options;
+        self
     }
+
+    pub fn unavailable(mut self, code: u32, message: &str) -> Self {
+        self.unavailable = Some(Frame::close(code, message));
+        self
+    }
+
+    pub fn offline(mut self) -> Self {
+        self.policy.offline = true;
+        self
+    }
+
+    pub fn fatal_errors(mut self, kinds: impl IntoIterator<Item = ErrorKind>) -> Self {
+        self.fatal_errors = kinds.into_iter().collect();
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn max_sessions(
+        mut self,
+        limits: impl Fn() -> SessionLimits + Send + Sync + 'static,
+    ) -> Self {
+        self.session_limits = Some(Box::new(limits));
+        self
+    }
+
+    pub fn access_list(
+        mut self,
+        access_list: impl Fn() -> AccessList + Send + Sync + 'static,
+    ) -> Self {
+        self.access_list = Some(Box::new(access_list));
+        self
+    }
+
+    pub fn coalesce_activity(mut self, window: Option<Duration>) -> Self {
+        self.policy.coalesce_activity = window;
+        self
+    }
+
+    pub fn dedup_activity(mut self, dedup: bool) -> Self {
+        self.policy.dedup_activity = dedup;
+        self
+    }
+
+    pub fn correct_clock_skew(mut self, correct: bool) -> Self {
+        self.policy.correct_clock_skew = correct;
+        self
+    }
+
+    pub fn rewrite_rules(mut self, rules: impl IntoIterator<Item = RewriteRule>) -> Self {
+        self.policy.rewrite_rules = rules.into_iter().collect();
+        self
+    }
+
+    pub fn script(mut self, script: Option<Script>) -> Self {
+        self.policy.script = script.map(Arc::new);
+        self
+    }
+
+    pub fn live_policy(
+        mut self,
+        policy: impl Fn() -> Arc<SessionPolicy> + Send + Sync + 'static,
+    ) -> Self {
+        self.live_policy = Some(Box::new(policy));
+        self
+    }
+
+    pub fn sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Applies everything in `config` which is about sessions, compiling its script if it has one.
+    pub fn link_config(mut self, config: &LinkConfig) -> anyhow::Result<Self> {
+        self.policy = SessionPolicy {
+            offline: self.policy.offline,
+            correct_clock_skew: self.policy.correct_clock_skew,
+            ..SessionPolicy::link_config(config)?
+        };
+
+        Ok(self.fatal_errors(config.fatal_errors.iter().copied()))
+    }
+
+    /// Adds `middleware` on top of the middleware added before it.
+    pub fn middleware(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// How a new session is to be served.
+    fn session_policy(&self) -> Arc<SessionPolicy> {
+        match &self.live_policy {
+            Some(policy) => policy(),
+            None => Arc::new(self.policy.clone()),
+        }
+    }
+
+    fn is_fatal(&self, kind: ErrorKind) -> bool {
+        self.fatal_errors.contains(&kind)
+    }
+}
+
+/// The handshake was answered with a Close frame which was sent by dip rather than Discord, such
+/// as a remote telling that Discord isn't running.
+#[derive(Debug)]
+struct Unavailable(Frame);
+
+impl Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "answered the handshake with a close frame: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// The number of sessions being served, in total and for each peer ip address.
+#[derive(Default)]
+struct SessionCount {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+
+    /// How many connections are yet to be told that they went over the limits.
+    rejecting: usize,
+}
+
+impl SessionCount {
+    /// Counts a new session from `ip`, unless that would go over `limits`, in which case the reason
+    /// it went over is returned.
+    fn admit(
+        count: &Arc<Mutex<Self>>,
+        ip: Option<IpAddr>,
+        limits: SessionLimits,
+    ) -> Result<Admitted, String> {
+        let mut this = count.lock().unwrap();
+
+        if let Some(max) = limits.total.filter(|&max| this.total >= max) {
+            return Err(format!("the limit of {max} sessions was reached"));
+        }
+
+        if let Some(ip) = ip {
+            let sessions = this.per_ip.get(&ip).copied().unwrap_or(0);
+
+            if let Some(max) = limits.per_ip.filter(|&max| sessions >= max) {
+                return Err(format!(
+                    "{ip} reached the limit of {max} sessions per ip address"
+                ));
+            }
+
+            *this.per_ip.entry(ip).or_default() += 1;
+        }
+
+        this.total += 1;
+
+        Ok(Admitted {
+            count: Arc::clone(count),
+            ip,
+        })
+    }
+
+    /// Counts a connection which went over the limits until it is told so, unless too many are
+    /// already waiting to be.
+    fn reject(count: &Arc<Mutex<Self>>) -> Option<Rejecting> {
+        let mut this = count.lock().unwrap();
+
+        if this.rejecting >= MAX_PENDING_REJECTIONS {
+            return None;
+        }
+
+        this.rejecting += 1;
+
+        Some(Rejecting {
+            count: Arc::clone(count),
+        })
+    }
+}
+
+/// A session which is counted in a [`SessionCount`] until this is dropped.
+struct Admitted {
+    count: Arc<Mutex<SessionCount>>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        let mut count = self.count.lock().unwrap();
+        count.total -= 1;
+
+        if let Some(ip) = self.ip {
+            if let Entry::Occupied(mut entry) = count.per_ip.entry(ip) {
+                *entry.get_mut() -= 1;
+
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+    }
+}
+
+/// A connection which is counted in a [`SessionCount`] as being rejected until this is dropped.
+struct Rejecting {
+    count: Arc<Mutex<SessionCount>>,
+}
+
+impl Drop for Rejecting {
+    fn drop(&mut self) {
+        self.count.lock().unwrap().rejecting -= 1;
+    }
+}
+
+/// The side of a session which is the link between host and remote, when heartbeats were
+/// negotiated on it.
+struct Link {
+    /// Sends frames over the link. This is weak so that the link is still closed once the other
+    /// side of the session is done sending.
+    sender: mpsc::WeakSender<Frame>,
+
+    /// When anything was last received over the link.
+    received: watch::Sender<Instant>,
+}
+
+impl Link {
+    /// Heartbeats are only exchanged on whichever side is the link between host and remote, if both
+    /// ends of it support them.
+    fn new(negotiated: Option<&Negotiated>, sender: &mpsc::Sender<Frame>) -> Option<Self> {
+        negotiated
+            .filter(|negotiated| negotiated.supports(link::HEARTBEAT))
+            .map(|_| Self {
+                sender: sender.downgrade(),
+                received: watch::channel(Instant::now()).0,
+            })
+    }
+}
+
+/// Spawns a task which writes every frame sent to the returned sender to `write_to`, until every
+/// sender is dropped.
+fn spawn_writer<W>(
+    write_to: W,
+    write_to_name: &'static str,
+) -> (mpsc::Sender<Frame>, JoinHandle<()>)
+where
+    W: WriteTo + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel::<Frame>(FRAME_BUFFER);
+    let handle = tokio::spawn(async move {
+        while let Some(frame) = receiver.recv().await {
+            if let Err(error) = frame.write(&write_to).await {
+                tracing::error!("failed to send frame to {write_to_name}: {error:#}");
+                break;
+            }
+        }
+    });
+
+    (sender, handle)
+}
+
+/// Spawns a task which reads every frame from `read_from` into the returned receiver, until
+/// `read_from` ends. Heartbeats are answered and swallowed if `link` is given.
+fn spawn_reader<R>(
+    read_from: R,
+    link: Option<Link>,
+    read_from_name: &'static str,
+) -> (mpsc::Receiver<Frame>, JoinHandle<()>)
+where
+    R: ReadFrom + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel::<Frame>(FRAME_BUFFER);
+    let handle = tokio::spawn(async move {
+        loop {
+            let frame = match Frame::read(&read_from).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    tracing::debug!("finished reading from {read_from_name}");
+                    break;
+                }
+                Err(error) => {
+                    tracing::error!("{error:#}");
+                    break;
+                }
+            };
+
+            if let Some(link) = &link {
+                link.received.send_replace(Instant::now());
+
+                match frame.opcode {
+                    opcode::HEARTBEAT => {
+                        if let Some(sender) = link.sender.upgrade() {
+                            let _ = sender.send(Frame::new(opcode::HEARTBEAT_ACK, [])).await;
+                        }
+
+                        continue;
+                    }
+                    opcode::HEARTBEAT_ACK => continue,
+                    _ => {}
+                }
+            }
+
+            if sender.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    (receiver, handle)
+}
+
+/// What happens to every frame on its way from one side of a session to the other, in the order
+/// of its fields.
+struct Pipeline {
+    direction: Direction,
+
+    /// Watches the answers to the activity updates which were forwarded the other way.
+    answers: Option<Answers>,
+
+    /// How many milliseconds to shift the timestamps of activities by.
+    clock_skew: Option<i64>,
+    rewriter: Option<Rewriter>,
+
+    /// The script to run, along with where to send its replies.
+    script: Option<(Arc<ScriptSession>, mpsc::Sender<Frame>)>,
+
+    /// The middleware to run, in the order frames go through them.
+    middleware: Vec<Arc<dyn Middleware>>,
+    context: Arc<Mutex<SessionContext>>,
+
+    activity_filter: Option<ActivityFilter>,
+}
+
+impl Pipeline {
+    /// Everything which happens to `frame` before it reaches the activity filter, which may turn
+    /// it into any number of frames.
+    async fn process(&self, mut frame: Frame) -> Vec<Frame> {
+        if let Some(answers) = &self.answers {
+            answers.answered(&frame);
+        }
+
+        if let Some(skew) = self.clock_skew {
+            activity::shift_timestamps(&mut frame, skew);
+        }
+
+        if let Some(rewriter) = &self.rewriter {
+            rewriter.rewrite(&mut frame);
+        }
+
+        let frames = match &self.script {
+            Some((script, reply_to)) => {
+                let outcome = script.on_frame(self.direction, frame);
+
+                for reply in outcome.reply {
+                    let _ = reply_to.send(reply).await;
+                }
+
+                outcome.forward
+            }
+            None => vec![frame],
+        };
+
+        frames
+            .into_iter()
+            .filter_map(|frame| {
+                through_middleware(&self.middleware, &self.context, self.direction, frame)
+            })
+            .collect()
+    }
+}
+
+/// Forwards every frame from one side of a session to the other, through `pipeline`. The response
+/// to the command with the nonce `answered` is dropped, as it was already answered while the
+/// session was offline.
+#[tracing::instrument(skip_all)]
+async fn forward(
+    mut read_from: mpsc::Receiver<Frame>,
+    write_to: mpsc::Sender<Frame>,
+    mut answered: Option<Value>,
+    mut pipeline: Pipeline,
+    read_from_name: &str,
+    write_to_name: &str,
+) {
+    tracing::trace!("{read_from_name}->{write_to_name} worker started");
+
+    'forward: loop {
+        let (frames, held) = tokio::select! {
+            frame = read_from.recv() => match frame {
+                Some(frame) => (pipeline.process(frame).await, false),
+                None => break,
+            },
+            frame = async {
+                match &mut pipeline.activity_filter {
+                    Some(filter) => filter.due().await,
+                    None => future::pending().await,
+                }
+            } => (vec![frame], true),
+        };
+
+        for frame in frames {
+            let frame = match &mut pipeline.activity_filter {
+                Some(filter) if !held => match filter.offer(frame).await {
+                    Some(frame) => frame,
+                    None => continue,
+                },
+                _ => frame,
+            };
+
+            if answered.is_some() && offline::nonce(&frame) == answered {
+                tracing::trace!("dropping {frame}, which was already answered while offline");
+                answered = None;
+                continue;
+            }
+
+            tracing::trace!("{read_from_name} -> {write_to_name}: {frame}");
+
+            if write_to.send(frame).await.is_err() {
+                break 'forward;
+            }
+        }
+    }
+
+    tracing::debug!("finished reading from {read_from_name} and sending to {write_to_name}");
+}
+
+/// Sends a heartbeat over the link every `interval`, returning once nothing has been received over
+/// it for `timeout`.
+async fn heartbeat(
+    sender: mpsc::WeakSender<Frame>,
+    received: watch::Receiver<Instant>,
+    interval: Duration,
+    timeout: Duration,
+    link_name: &str,
+) {
+    let mut interval = time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let silent_for = received.borrow().elapsed();
+
+        if silent_for >= timeout {
+            tracing::warn!(
+                "nothing was received from {link_name} for {} seconds, so the link is considered \
+                 dead; tearing down the session",
+                silent_for.as_secs()
+            );
+            return;
+        }
+
+        // once the link is being closed, it's up to the other side to finish the session
+        let Some(sender) = sender.upgrade() else {
+            return future::pending().await;
+        };
+
+        let _ = sender.send(Frame::new(opcode::HEARTBEAT, [])).await;
+    }
+}
+
+/// Connects to `stream_connect_to`, runs the preamble if there is one, forwards `handshake` to it,
+/// then waits for it to be answered.
+async fn handshake_with<S, SS>(
+    stream_connect_to: SS,
+    handshake: &Frame,
+    preamble: Option<&Hello>,
+    link_options: &LinkOptions,
+    stream_name: &str,
+) -> anyhow::Result<(S, Frame, Option<Negotiated>)>
+where
+    SS: Displayable + Send + 'static,
+    S: ServableStream<SS>,
+{
+    let mut stream = time::timeout(CONNECT_TIMEOUT, S::connect(stream_connect_to))
+        .await
+        .context("timed out connecting")?
+        .context("failed to connect")?;
+
+    if let Err(error) = stream.configure(link_options) {
+        tracing::warn!("failed to configure connection to {stream_name}: {error}");
+    }
+
+    let (read_half, write_half) = stream.split();
+    let mut negotiated = None;
+
+    if let Some(hello) = preamble {
+        let peer = time::timeout(
+            HANDSHAKE_TIMEOUT,
+            preamble::initiate(&read_half, &write_half, hello, stream_name),
+        )
+        .await
+        .with_context(|| format!("{stream_name} did not answer the preamble in time"))??;
+        tracing::debug!("{stream_name} is {peer}");
+        negotiated = Some(peer);
+    }
+
+    handshake
+        .write(&write_half)
+        .await
+        .context("failed to forward the handshake")?;
+
+    let response = time::timeout(HANDSHAKE_TIMEOUT, Frame::read(&read_half))
+        .await
+        .context("timed out waiting for the handshake to be answered")??
+        .context("connection closed before the handshake was answered")?;
+
+    if response
+        .close_code()
+        .is_some_and(|code| close_code::DIP.contains(&code))
+    {
+        return Err(Unavailable(response).into());
+    }
+
+    drop((read_half, write_half));
+    Ok((stream, response, negotiated))
+}
+
+/// Tries each of `candidates` in order, returning the first one which answers `handshake`. If none
+/// of them do, the Close frame to answer the handshake with is returned along with the error.
+async fn connect<S, SS>(
+    candidates: Vec<SS>,
+    handshake: &Frame,
+    hooks: &ServeHooks,
+    link_options: &LinkOptions,
+    stream_name: &str,
+) -> Result<(S, Frame, Option<Negotiated>), (anyhow::Error, Option<Frame>)>
+where
+    SS: Displayable + Send + 'static,
+    S: ServableStream<SS>,
+{
+    let mut last_error = None;
+    let mut close = hooks.unavailable.clone();
+
+    for candidate in candidates {
+        let address = candidate.display().to_string();
+        tracing::debug!("creating new connection to {stream_name} at {address}");
+
+        let preamble = hooks.stream_preamble.as_ref();
+
+        match handshake_with::<S, SS>(candidate, handshake, preamble, link_options, stream_name)
+            .await
+        {
+            Ok(connected) => {
+                tracing::info!("session served by {stream_name} at {address}");
+                return Ok(connected);
+            }
+            Err(error) => {
+                // a candidate which could tell why it's unavailable knows better than we do
+                if let Some(Unavailable(frame)) = error.downcast_ref() {
+                    close = Some(frame.clone());
+                }
+
+                let error = error.context(format!("{stream_name} at {address} is unavailable"));
+                tracing::warn!("{error:#}");
+                last_error = Some(error);
+            }
+        }
+    }
+
+    let error = last_error
+        .unwrap_or_else(|| anyhow::anyhow!("there is no {stream_name} to connect to"))
+        .context(format!("failed to connect to any {stream_name}"));
+
+    Err((error, close))
+}
+
+/// Waits for `writers` to write whatever is still queued once all of their senders are dropped,
+/// giving up after [`DRAIN_TIMEOUT`].
+async fn drain(writers: &mut [JoinHandle<()>]) {
+    if time::timeout(DRAIN_TIMEOUT, future::join_all(writers.iter_mut()))
+        .await
+        .is_err()
+    {
+        for writer in writers {
+            writer.abort();
+        }
+    }
+}
+
+/// Ends a session before anything was forwarded, once whatever was already sent to the new client
+/// is written.
+async fn hang_up(
+    to_new_client: mpsc::Sender<Frame>,
+    new_client_reader: JoinHandle<()>,
+    new_client_writer: JoinHandle<()>,
+) {
+    new_client_reader.abort();
+    drop(to_new_client);
+    drain(&mut [new_client_writer]).await;
+}
+
+/// The Close frame sent to new clients whose session is ended by a shutdown.
+fn shutting_down() -> Frame {
+    Frame::close(close_code::SHUTTING_DOWN, "dip is shutting down")
+}
+
+/// Returns the addresses to connect to for a session, in order of preference.
+pub(crate) type StreamConnectTo<SS> = Arc<dyn Fn() -> Vec<SS> + Send + Sync>;
+
+/// Answers the new client in place of the stream until the stream can be connected to, which is
+/// retried every [`offline::RETRY_INTERVAL`]. Returns `None` if the new client went away or a
+/// shutdown was requested first, otherwise the connection along with the activity which is still to
+/// be set.
+async fn offline<S, SS>(
+    from_new_client: &mut mpsc::Receiver<Frame>,
+    to_new_client: &mpsc::Sender<Frame>,
+    handshake: &Frame,
+    stream_connect_to: &StreamConnectTo<SS>,
+    hooks: &ServeHooks,
+    link_options: &LinkOptions,
+    stream_name: &str,
+) -> Option<(S, Option<Negotiated>, Option<Frame>)>
+where
+    SS: Displayable + Send + 'static,
+    S: ServableStream<SS>,
+{
+    let mut emulator = Emulator::new(handshake);
+    tracing::info!(
+        "no {stream_name} is reachable, so the session of app {} is offline until one is",
+        emulator.client_id()
+    );
+    to_new_client.send(emulator.ready()).await.ok()?;
+
+    let start = time::Instant::now() + offline::RETRY_INTERVAL;
+    let mut retry = time::interval_at(start, offline::RETRY_INTERVAL);
+    retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // reconnecting runs alongside answering the new client, so that it isn't left waiting on it
+    let mut reconnecting = None;
+
+    loop {
+        tokio::select! {
+            frame = from_new_client.recv() => match emulator.answer(frame?) {
+                Answer::Reply(reply) => to_new_client.send(reply).await.ok()?,
+                Answer::Close => return None,
+            },
+            () = hooks.shutdown.requested() => {
+                let _ = to_new_client.send(shutting_down()).await;
+                return None;
+            }
+            _ = retry.tick(), if reconnecting.is_none() => {
+                let candidates = stream_connect_to();
+                reconnecting = Some(Box::pin(connect::<S, SS>(
+                    candidates,
+                    handshake,
+                    hooks,
+                    link_options,
+                    stream_name,
+                )));
+            }
+            Some(connected) = OptionFuture::from(reconnecting.as_mut()) => {
+                reconnecting = None;
+
+                // the new client was already answered with a READY event of our own
+                if let Ok((stream, _, negotiated)) = connected {
+                    tracing::info!("the session of app {} is back online", emulator.client_id());
+                    return Some((stream, negotiated, emulator.take_activity()));
+                }
+
+                retry.reset();
+            }
+        }
+    }
+}
+
+/// Proxies a single connection from a new client to the first of the addresses returned by
+/// `stream_connect_to` which answers its handshake. An error is only returned if none of them do.
+///
+/// If the session was `rejected`, the handshake is answered with that Close frame instead.
+async fn session<N, S, SS>(
+    new_client: N,
+    rejected: Option<Frame>,
+    stream_connect_to: StreamConnectTo<SS>,
+    new_client_name: &'static str,
+    stream_name: &'static str,
+    hooks: Arc<ServeHooks>,
+    context: Arc<Mutex<SessionContext>>,
+) -> anyhow::Result<()>
+where
+    N: SplittableStream,
+    SS: Displayable + Send + 'static,
+    S: ServableStream<SS>,
+{
+    let policy = hooks.session_policy();
+
+    if let Err(error) = new_client.configure(&policy.link_options) {
+        tracing::warn!("failed to configure connection from {new_client_name}: {error}");
+    }
+
+    let (new_client_read_half, new_client_write_half) = new_client.into_split();
+    let mut new_client_negotiated = None;
+
+    if let Some(hello) = &hooks.new_client_preamble {
+        let result = time::timeout(
+            HANDSHAKE_TIMEOUT,
+            preamble::respond(
+                &new_client_read_half,
+                &new_client_write_half,
+                hello,
+                new_client_name,
+            ),
+        )
+        .await
+        .with_context(|| format!("{new_client_name} did not send a preamble in time"))
+        .and_then(|result| result);
+
+        // a client which can't talk to us is its own problem, so this only ends its session
+        match result {
+            Ok(negotiated) => {
+                tracing::debug!("{new_client_name} is {negotiated}");
+                new_client_negotiated = Some(negotiated);
+            }
+            Err(error) => {
+                tracing::error!("{error:#}");
+                return Ok(());
+            }
+        }
+    }
+
+    // the timestamps of activities are taken from the clock of whoever sent them, which is the
+    // new client
+    let clock_skew = new_client_negotiated
+        .as_ref()
+        .and_then(|negotiated| negotiated.clock_offset)
+        .filter(|offset| policy.correct_clock_skew && offset.abs() >= CLOCK_SKEW_THRESHOLD)
+        .map(|offset| {
+            tracing::info!(
+                "the clock of {new_client_name} is {offset} ms ahead, so activity timestamps are \
+                 shifted by {} ms",
+                -offset
+            );
+            -offset
+        });
+
+    let (to_new_client, new_client_writer) = spawn_writer(new_client_write_half, new_client_name);
+    let new_client_link = Link::new(new_client_negotiated.as_ref(), &to_new_client);
+    let new_client_heartbeat = new_client_link.as_ref().map(|link| {
+        (
+            link.sender.clone(),
+            link.received.subscribe(),
+            new_client_name,
+        )
+    });
+    let (mut from_new_client, new_client_reader) =
+        spawn_reader(new_client_read_half, new_client_link, new_client_name);
+
+    let handshake = match time::timeout(HANDSHAKE_TIMEOUT, from_new_client.recv()).await {
+        Ok(Some(handshake)) => handshake,
+        Ok(None) => {
+            tracing::debug!("{new_client_name} disconnected before sending a handshake");
+            return Ok(());
+        }
+        Err(_) => {
+            tracing::warn!("{new_client_name} did not send a handshake in time");
+            hang_up(to_new_client, new_client_reader, new_client_writer).await;
+            return Ok(());
+        }
+    };
+
+    if let Some(close) = rejected {
+        let _ = to_new_client.send(close).await;
+        hang_up(to_new_client, new_client_reader, new_client_writer).await;
+        return Ok(());
+    }
+
+    context.lock().unwrap().client_id = handshake.client_id();
+
+    let script = policy.script.as_ref().map(|script| {
+        Arc::new(ScriptSession::new(
+            Arc::clone(script),
+            handshake.client_id(),
+        ))
+    });
+
+    // what the script sends on besides the handshake is sent once connected
+    let mut after_handshake = Vec::new();
+
+    let handshake = match &script {
+        Some(script) => {
+            let outcome = script.on_handshake(handshake);
+
+            for reply in outcome.reply {
+                let _ = to_new_client.send(reply).await;
+            }
+
+            let mut forward = outcome.forward.into_iter();
+
+            let Some(handshake) = forward.next() else {
+                tracing::debug!("the script dropped the handshake of {new_client_name}");
+                hang_up(to_new_client, new_client_reader, new_client_writer).await;
+                return Ok(());
+            };
+
+            after_handshake.extend(forward);
+            handshake
+        }
+        None => handshake,
+    };
+
+    let outgoing =
+        |frame| through_middleware(&hooks.middleware, &context, Direction::Outgoing, frame);
+
+    let Some(handshake) = outgoing(handshake) else {
+        tracing::debug!("middleware dropped the handshake of {new_client_name}");
+        hang_up(to_new_client, new_client_reader, new_client_writer).await;
+        return Ok(());
+    };
+
+    let after_handshake = after_handshake
+        .into_iter()
+        .filter_map(outgoing)
+        .collect::<Vec<_>>();
+
+    let connected = match connect::<S, SS>(
+        stream_connect_to(),
+        &handshake,
+        &hooks,
+        &policy.link_options,
+        stream_name,
+    )
+    .await
+    {
+        Ok((stream, response, negotiated)) => Some((stream, negotiated, Some(response), None)),
+        Err((error, close)) => {
+            if let Some(hook) = &hooks.on_stream_connect_fail {
+                hook(&error)
+            }
+
+            if !policy.offline {
+                if let Some(close) = close {
+                    let _ = to_new_client.send(close).await;
+                }
+
+                hang_up(to_new_client, new_client_reader, new_client_writer).await;
+                return Err(error);
+            }
+
+            offline::<S, SS>(
+                &mut from_new_client,
+                &to_new_client,
+                &handshake,
+                &stream_connect_to,
+                &hooks,
+                &policy.link_options,
+                stream_name,
+            )
+            .await
+            .map(|(stream, negotiated, activity)| (stream, negotiated, None, activity))
+        }
+    };
+
+    let Some((stream, stream_negotiated, response, activity)) = connected else {
+        tracing::debug!("{new_client_name} closed the session while offline");
+        hang_up(to_new_client, new_client_reader, new_client_writer).await;
+        return Ok(());
+    };
+
+    let (stream_read_half, stream_write_half) = stream.into_split();
+    let (to_stream, stream_writer) = spawn_writer(stream_write_half, stream_name);
+    let stream_link = Link::new(stream_negotiated.as_ref(), &to_stream);
+    let stream_heartbeat = stream_link
+        .as_ref()
+        .map(|link| (link.sender.clone(), link.received.subscribe(), stream_name));
+    let (from_stream, stream_reader) = spawn_reader(stream_read_half, stream_link, stream_name);
+
+    tracing::debug!("created new connection to {stream_name}");
+
+    {
+        let mut context = context.lock().unwrap();
+        context.connected = Some(Instant::now());
+
+        for middleware in &hooks.middleware {
+            middleware.on_connect(&mut context);
+        }
+    }
+
+    let client_id = handshake.client_id();
+    let rewriter = (!policy.rewrite_rules.is_empty())
+        .then(|| Rewriter::new(client_id.clone(), Arc::clone(&policy.rewrite_rules)));
+    let activity_filter =
+        (policy.coalesce_activity.is_some() || policy.dedup_activity).then(|| {
+            let client_id = client_id.unwrap_or_else(|| "unknown".to_owned());
+            let mut filter = ActivityFilter::new(client_id, to_new_client.clone());
+
+            if let Some(window) = policy.coalesce_activity {
+                filter = filter.coalesce(window);
+            }
+
+            if policy.dedup_activity {
+                filter = filter.dedup();
+            }
+
+            filter
+        });
+
+    // the script gets to send its last frames once the session ends, however it ends
+    let close = script
+        .clone()
+        .map(|script| (script, to_stream.clone(), Arc::clone(&context)));
+    let answers = activity_filter
+        .as_ref()
+        .filter(|_| policy.dedup_activity)
+        .map(ActivityFilter::answers);
+    let pipeline = Pipeline {
+        direction: Direction::Outgoing,
+        answers: None,
+        clock_skew,
+        rewriter,
+        script: script.clone().map(|script| (script, to_new_client.clone())),
+        middleware: hooks.middleware.clone(),
+        context: Arc::clone(&context),
+        activity_filter,
+    };
+    let stream_pipeline = Pipeline {
+        direction: Direction::Incoming,
+        answers,
+        clock_skew: None,
+        rewriter: None,
+        script: script.map(|script| (script, to_stream.clone())),
+        middleware: hooks.middleware.iter().rev().cloned().collect(),
+        context,
+        activity_filter: None,
+    };
+
+    if let Some(response) = response {
+        for frame in stream_pipeline.process(response).await {
+            let _ = to_new_client.send(frame).await;
+        }
+    }
+
+    for frame in after_handshake {
+        let _ = to_stream.send(frame).await;
+    }
+
+    // the app carries on from where it was while offline, so what it last set is set for real
+    let mut answered = None;
+
+    if let Some(activity) = activity {
+        answered = offline::nonce(&activity);
+
+        for frame in pipeline.process(activity).await {
+            let _ = to_stream.send(frame).await;
+        }
+    }
+
+    let heartbeat = new_client_heartbeat
+        .or(stream_heartbeat)
+        .zip(policy.link_options.heartbeat_interval)
+        .map(|((sender, received, link_name), interval)| {
+            heartbeat(
+                sender,
+                received,
+                interval,
+                policy.link_options.heartbeat_timeout,
+                link_name,
+            )
+        });
+
+    let mut nc2s = tokio::spawn(async move {
+        forward(
+            from_new_client,
+            to_stream,
+            None,
+            pipeline,
+            new_client_name,
+            stream_name,
+        )
+        .await
+    });
+
+    let closing = to_new_client.downgrade();
+    let mut s2nc = tokio::spawn(async move {
+        forward(
+            from_stream,
+            to_new_client,
+            answered,
+            stream_pipeline,
+            stream_name,
+            new_client_name,
+        )
+        .await
+    });
+
+    let heartbeat = async {
+        match heartbeat {
+            Some(heartbeat) => heartbeat.await,
+            None => future::pending().await,
+        }
+    };
+
+    let readers = [new_client_reader, stream_reader];
+    let mut writers = [new_client_writer, stream_writer];
+
+    // the session is over as soon as either direction ends, as the other one would otherwise keep
+    // waiting on a socket which nobody is going to write to anymore
+    let remaining = tokio::select! {
+        _ = &mut nc2s => {
+            tracing::debug!("{new_client_name} closed the session");
+            Some(s2nc)
+        }
+        _ = &mut s2nc => {
+            tracing::debug!("{stream_name} closed the session");
+            Some(nc2s)
+        }
+        () = heartbeat => {
+            for task in readers.iter().chain([&nc2s, &s2nc]) {
+                task.abort();
+            }
+
+            None
+        }
+        () = hooks.shutdown.requested() => {
+            // the new client is told why, after whatever was already on its way to it
+            let to_new_client = closing.upgrade();
+            nc2s.abort();
+            s2nc.abort();
+            let _ = s2nc.await;
+
+            if let Some(to_new_client) = to_new_client {
+                let _ = to_new_client.send(shutting_down()).await;
+            }
+
+            None
+        }
+    };
+
+    if let Some((script, to_stream, context)) = close {
+        let outcome = script.on_close();
+        let to_new_client = closing.upgrade();
+
+        let sent = time::timeout(DRAIN_TIMEOUT, async {
+            for frame in outcome.forward {
+                let outgoing =
+                    through_middleware(&hooks.middleware, &context, Direction::Outgoing, frame);
+
+                if let Some(frame) = outgoing {
+                    let _ = to_stream.send(frame).await;
+                }
+            }
+
+            if let Some(to_new_client) = &to_new_client {
+                for reply in outcome.reply {
+                    let _ = to_new_client.send(reply).await;
+                }
+            }
+        })
+        .await;
+
+        if sent.is_err() {
+            tracing::warn!("the frames sent by the script as the session closed were held up");
+        }
+    }
+
+    if let Some(mut remaining) = remaining {
+        if time::timeout(policy.link_options.linger, &mut remaining)
+            .await
+            .is_err()
+        {
+            remaining.abort();
+        }
+    }
+
+    // with both directions done, the writers finish once they've written whatever is still queued
+    for reader in readers {
+        reader.abort();
+    }
+
+    drain(&mut writers).await;
+
+    tracing::info!("connection to {stream_name} closed");
+    Ok(())
+}
+
+/// Accepts connections from `listener_bind_to`, and forwards each of them to the first of the
+/// addresses returned by `stream_connect_to` which answers the handshake of the new client.
+/// `stream_connect_to` is called once for every new connection, and returns the addresses in
+/// order of preference. Returns once a shutdown is requested through the hooks, or a fatal error
+/// happened.
+pub async fn serve<L, S, LS, SS>(
+    listener_bind_to: LS,
+    stream_connect_to: impl Fn() -> Vec<SS> + Send + Sync + 'static,
+    new_client_name: &'static str,
+    stream_name: &'static str,
+    hooks: ServeHooks,
+) -> anyhow::Result<()>
+where
+    LS: Displayable + Send + 'static,
+    L: ServableListener<LS>,
+    L::Stream: Send + 'static,
+    L::SocketAddr: Debug,
+    SS: Displayable + Send + 'static,
+    S: ServableStream<SS> + Send + 'static,
+{
+    let listener = bind::<L, LS>(listener_bind_to).await?;
+    let stream_connect_to: StreamConnectTo<SS> = Arc::new(stream_connect_to);
+
+    serve_on::<L, S, LS, SS>(
+        listener,
+        stream_connect_to,
+        new_client_name,
+        stream_name,
+        hooks,
+    )
+    .await
+}
+
+pub(crate) async fn bind<L, LS>(bind_to: LS) -> anyhow::Result<L>
+where
+    LS: Displayable + Send + 'static,
+    L: ServableListener<LS>,
+{
+    let error_message = format!("failed to bind to {}", bind_to.display());
+    L::bind(bind_to).await.context(error_message)
+}
+
+/// Like [`serve`], but with a listener which is already bound.
+pub(crate) async fn serve_on<L, S, LS, SS>(
+    listener: L,
+    stream_connect_to: StreamConnectTo<SS>,
+    new_client_name: &'static str,
+    stream_name: &'static str,
+    hooks: ServeHooks,
+) -> anyhow::Result<()>
+where
+    LS: Displayable + Send + 'static,
+    L: ServableListener<LS>,
+    L::Stream: Send + 'static,
+    L::SocketAddr: Debug,
+    SS: Displayable + Send + 'static,
+    S: ServableStream<SS> + Send + 'static,
+{
+    tracing::debug!("start serving connections");
+    let hooks = Arc::new(hooks);
+
+    // sessions are handled in their own tasks so that a slow handshake doesn't hold up accepting
+    // other connections, and so that a failing session only takes down serving if told to
+    let mut sessions = JoinSet::new();
+    let count = Arc::default();
+    let mut next_id = 0;
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(joined) = sessions.join_next() => {
+                match joined {
+                    Ok(Err(error)) if hooks.is_fatal(ErrorKind::Connect) => return Err(error),
+                    Ok(Err(error)) => tracing::error!("{error:#}"),
+                    Err(error) if error.is_panic() => tracing::error!("session panicked: {error}"),
+                    Ok(Ok(())) | Err(_) => {}
+                }
+
+                continue;
+            }
+            () = hooks.shutdown.requested() => break,
+        };
+
+        let (stream, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                let error = anyhow::Error::new(error).context("failed to accept new connection");
+
+                if hooks.is_fatal(ErrorKind::Accept) {
+                    return Err(error);
+                }
+
+                tracing::error!("{error:#}");
+                time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let ip = L::peer_ip(&addr);
+
+        // nothing is opened for peers which aren't allowed, not even the stream
+        let permitted = hooks
+            .access_list
+            .as_ref()
+            .is_none_or(|access_list| access_list().permits(ip));
+
+        if !permitted {
+            tracing::warn!(
+                ?addr,
+                "denied connection from {new_client_name} by the access list"
+            );
+            continue;
+        }
+
+        tracing::info!(?addr, "new connection from {new_client_name} incoming");
+
+        // a rejected connection is still answered, so that the app can be told why
+        let limits = hooks
+            .session_limits
+            .as_ref()
+            .map_or_else(SessionLimits::default, |limits| limits());
+
+        let (admission, rejected) = match SessionCount::admit(&count, ip, limits) {
+            Ok(admitted) => (Ok(admitted), None),
+            Err(reason) => {
+                let Some(rejecting) = SessionCount::reject(&count) else {
+                    tracing::warn!(
+                        ?addr,
+                        "closing connection from {new_client_name} straight away: {reason}, and \
+                         too many other connections are already being rejected"
+                    );
+                    continue;
+                };
+
+                tracing::warn!(
+                    ?addr,
+                    "rejecting connection from {new_client_name}: {reason}"
+                );
+                let close = Frame::close(close_code::TOO_MANY_SESSIONS, "too many sessions");
+                (Err(rejecting), Some(close))
+            }
+        };
+
+        let context = SessionContext::new(next_id, ip, new_client_name, stream_name);
+        let context = Arc::new(Mutex::new(context));
+        next_id += 1;
+
+        let mut closing = Closing::new(&context, &hooks.sessions);
+        let rejected = rejected.or_else(|| {
+            let rejected = closing.accept(&hooks.middleware);
+
+            if rejected.is_some() {
+                tracing::debug!(
+                    ?addr,
+                    "middleware rejected connection from {new_client_name}"
+                );
+            }
+
+            rejected
+        });
+
+        let session = session::<_, S, SS>(
+            stream,
+            rejected,
+            Arc::clone(&stream_connect_to),
+            new_client_name,
+            stream_name,
+            Arc::clone(&hooks),
+            context,
+        );
+
+        sessions.spawn(async move {
+            let _admission = admission;
+            let _closing = closing;
+            session.await
+        });
+    }
+
+    // the listener is closed first, so that nothing new comes in while sessions are closing
+    drop(listener);
+
+    if !sessions.is_empty() {
+        tracing::info!(
+            "waiting up to {} seconds for {} session(s) to close",
+            SHUTDOWN_TIMEOUT.as_secs(),
+            sessions.len()
+        );
+    }
+
+    let closed = time::timeout(SHUTDOWN_TIMEOUT, async {
+        while sessions.join_next().await.is_some() {}
+    })
+    .await;
+
+    if closed.is_err() {
+        tracing::warn!(
+            "aborting {} session(s) which did not close in time",
+            sessions.len()
+        );
+        sessions.shutdown().await;
+    }
+
+    tracing::info!("stopped serving connections");
+    Ok(())
 }
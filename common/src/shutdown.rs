@@ -0,0 +1,120 @@
+//! Graceful shutdown. Once a shutdown is requested, such as by a signal, [`serve`](crate::serve)
+//! stops accepting new connections, and active sessions are sent a Close frame and given a moment
+//! to drain before it returns. The files registered with [`Shutdown::remove_on_exit`] are then
+//! removed by whoever was serving, before the process exits.
+
+use fs_err::tokio as fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[cfg(unix)]
+use {
+    anyhow::Context,
+    tokio::signal::unix::{signal, SignalKind},
+};
+
+/// How long active sessions are given to close once a shutdown is requested, before they are
+/// aborted.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A handle to request a shutdown, or to wait for one to be requested. Clones of it share the same
+/// state.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<watch::Sender<bool>>,
+    remove_on_exit: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            requested: Arc::new(watch::channel(false).0),
+            remove_on_exit: Arc::default(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Spawns a task which requests a shutdown on SIGTERM, SIGINT or SIGQUIT (or Ctrl+C on
+    /// windows).
+    pub fn on_signals() -> anyhow::Result<Self> {
+        let shutdown = Self::default();
+
+        #[cfg(unix)]
+        {
+            let signals = [
+                SignalKind::terminate(),
+                SignalKind::interrupt(),
+                SignalKind::quit(),
+            ]
+            .into_iter()
+            .map(signal)
+            .collect::<Result<Vec<_>, _>>()
+            .context("failed to register SIGTERM, SIGINT, and SIGQUIT signals")?;
+
+            for mut signal in signals {
+                let shutdown = shutdown.clone();
+
+                tokio::spawn(async move {
+                    if signal.recv().await.is_some() {
+                        tracing::info!("received termination signal, shutting down");
+                        shutdown.request();
+                    }
+                });
+            }
+        }
+
+        #[cfg(windows)]
+        {
+            let shutdown = shutdown.clone();
+
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    tracing::info!("received Ctrl+C, shutting down");
+                    shutdown.request();
+                }
+            });
+        }
+
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.requested.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Waits until a shutdown is requested.
+    pub async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+
+        // the sender lives as long as `self` does, so this can't fail
+        while !*requested.borrow_and_update() {
+            let _ = requested.changed().await;
+        }
+    }
+
+    /// Registers `path` to be removed by [`Shutdown::remove_files`].
+    pub fn remove_on_exit(&self, path: PathBuf) {
+        tracing::debug!("removing {} on exit", path.display());
+        self.remove_on_exit.lock().unwrap().push(path);
+    }
+
+    /// Removes every file registered with [`Shutdown::remove_on_exit`]. This should be called once
+    /// serving is done, whether or not it failed.
+    pub async fn remove_files(&self) {
+        let paths = std::mem::take(&mut *self.remove_on_exit.lock().unwrap());
+
+        for path in paths {
+            match fs::remove_file(&path).await {
+                Ok(()) => tracing::debug!(?path, "removed file"),
+                Err(error) => tracing::error!("failed to remove file: {error}"),
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::serve::{ServableListener, ServableStream};
+use crate::serve::{ServableListener, ServableStream, SplittableStream};
 use crate::{ReadFrom, WriteTo};
 use async_trait::async_trait;
 use std::ffi::{OsStr, OsString};
@@ -41,17 +41,12 @@ impl<S: Into<OsString> + Send + 'static> ServableListener<S> for ServableNamedPi
     }
 }
 
-#[async_trait]
-impl<S: Send + 'static> ServableStream<S> for NamedPipeServer {
+impl SplittableStream for NamedPipeServer {
     type OwnedReadHalf = NamedPipeServerOwnedReadHalf;
     type OwnedWriteHalf = NamedPipeServerOwnedWriteHalf;
     type ReadHalf<'a> = NamedPipeServerReadHalf<'a> where Self: 'a;
     type WriteHalf<'a> = NamedPipeServerWriteHalf<'a> where Self: 'a;
 
-    async fn connect(_: S) -> io::Result<Self> {
-        panic!("not a NamedPipeClient")
-    }
-
     fn into_split(self) -> (Self::OwnedReadHalf, Self::OwnedWriteHalf) {
         let this = Arc::new(self);
 
@@ -113,29 +108,12 @@ impl_ReadFrom_for!(lt NamedPipeServerReadHalf);
 pub struct NamedPipeServerWriteHalf<'a>(&'a NamedPipeServer);
 impl_WriteTo_for!(lt NamedPipeServerWriteHalf);
 
-#[async_trait]
-impl<S: AsRef<OsStr> + Send + 'static> ServableStream<S> for NamedPipeClient {
+impl SplittableStream for NamedPipeClient {
     type OwnedReadHalf = NamedPipeClientOwnedReadHalf;
     type OwnedWriteHalf = NamedPipeClientOwnedWriteHalf;
     type ReadHalf<'a> = NamedPipeClientReadHalf<'a> where Self: 'a;
     type WriteHalf<'a> = NamedPipeClientWriteHalf<'a> where Self: 'a;
 
-    async fn connect(socket: S) -> io::Result<Self> {
-        const ERROR_PIPE_BUSY: i32 = 231;
-
-        let address = socket.as_ref();
-
-        loop {
-            match ClientOptions::new().open(address) {
-                Ok(client) => break Ok(client),
-                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
-                    time::sleep(Duration::from_millis(50)).await
-                }
-                Err(e) => return Err(e),
-            }
-        }
-    }
-
     fn into_split(self) -> (Self::OwnedReadHalf, Self::OwnedWriteHalf) {
         let this = Arc::new(self);
 
@@ -153,6 +131,25 @@ impl<S: AsRef<OsStr> + Send + 'static> ServableStream<S> for NamedPipeClient {
     }
 }
 
+#[async_trait]
+impl<S: AsRef<OsStr> + Send + 'static> ServableStream<S> for NamedPipeClient {
+    async fn connect(socket: S) -> io::Result<Self> {
+        const ERROR_PIPE_BUSY: i32 = 231;
+
+        let address = socket.as_ref();
+
+        loop {
+            match ClientOptions::new().open(address) {
+                Ok(client) => break Ok(client),
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                    time::sleep(Duration::from_millis(50)).await
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
 pub struct NamedPipeClientOwnedReadHalf(Arc<NamedPipeClient>);
 impl_ReadFrom_for_OwnedReadHalf!(NamedPipeClientOwnedReadHalf);
 
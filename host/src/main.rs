@@ -1,40 +1,145 @@
 mod utils;
 
 use crate::utils::MaybeSocketAddr;
-use anyhow::Context;
 use clap::Parser;
-use dip_common::config::ConfigLike;
-use dip_common::serve::ServeHooks;
+use dip_common::config::{CommonArgs, ConfigLike};
+use dip_common::discovery::{Discovery, DISCOVERY_TIMEOUT};
+use dip_common::frame::close_code;
+use dip_common::link::LinkConfig;
+use dip_common::preamble::Hello;
+use dip_common::serve::{Displayable, ServeHooks, SessionPolicy};
+use dip_common::shutdown::Shutdown;
 use dip_common::DEFAULT_PORT;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::process::ExitCode;
-use std::sync::OnceLock;
+use std::sync::Arc;
 use tokio::net::{TcpStream, UnixListener};
+use tokio::time;
 
-#[derive(Serialize, Deserialize, Parser)]
+#[derive(Serialize, Deserialize, Parser, Default)]
 #[command(author, version, about)]
 pub struct Config {
     /// The location of the Discord IPC path. If not specified, it will be automatically detected.
     #[clap(short = 'p', long)]
     pub discord_ipc_path: Option<PathBuf>,
 
-    /// The remote address that the host will connect and forward packets to.
+    /// The remote addresses that the host will connect and forward packets to, in order of
+    /// priority. Each new session is forwarded to the first remote which has Discord running. If
+    /// not specified, remotes are discovered on the local network instead.
     #[clap(short, long)]
-    pub remote_address: Option<MaybeSocketAddr>,
+    #[serde(default, deserialize_with = "utils::one_or_many")]
+    pub remote_address: Vec<MaybeSocketAddr>,
 
     /// Whether or not to keep the unix socket created by this program on exit.
     #[clap(short, long)]
     #[serde(default)]
     pub keep_socket: bool,
+
+    /// The log filter, in the same syntax as the `RUST_LOG` environment variable. If not
+    /// specified, `RUST_LOG` is used, falling back to `info`.
+    #[clap(long)]
+    pub log: Option<String>,
+
+    /// Whether or not to reload the configuration when the configuration file changes. It is
+    /// always reloaded on SIGHUP.
+    #[clap(long)]
+    #[serde(default)]
+    pub watch_config: bool,
+
+    /// Whether or not to answer apps in place of Discord while no remote is reachable, instead of
+    /// closing their connection. Their latest activity is set once a remote becomes reachable.
+    #[clap(long)]
+    #[serde(default)]
+    pub offline: bool,
+
+    /// List the remotes which can be discovered on the local network, then exit.
+    #[clap(long)]
+    #[serde(skip)]
+    pub list_remotes: bool,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub link: LinkConfig,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    pub common: CommonArgs,
 }
 
 impl<'de> ConfigLike<'de> for Config {
     const FILE_NAME: &'static str = "host.toml";
+    const SECTION: &'static str = "host";
+    const RELOADABLE: &'static [&'static str] = &[
+        "remote_address",
+        "log",
+        "watch_config",
+        "offline",
+        "heartbeat_interval",
+        "heartbeat_timeout",
+        "tcp_keepalive",
+        "tcp_nodelay",
+        "linger",
+        "coalesce_activity",
+        "dedup_activity",
+        "script",
+        "script_budget",
+        "rewrite",
+    ];
 
     fn discord_ipc_path(&self) -> &Option<PathBuf> {
         &self.discord_ipc_path
     }
+
+    fn common_args(&self) -> &CommonArgs {
+        &self.common
+    }
+
+    fn log(&self) -> Option<&str> {
+        self.log.as_deref()
+    }
+
+    fn watch_config(&self) -> bool {
+        self.watch_config
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.link.validate()
+    }
+}
+
+/// The remote addresses to connect to, which are the discovered ones if none are set.
+fn remote_addresses(config: &Config, discovery: Option<&Discovery>) -> Vec<(String, u16)> {
+    match discovery {
+        Some(discovery) if config.remote_address.is_empty() => discovery
+            .remotes()
+            .into_iter()
+            .map(|remote| (remote.address.ip().to_string(), remote.address.port()))
+            .collect(),
+        _ => config
+            .remote_address
+            .iter()
+            .map(|address| address.with_port(DEFAULT_PORT))
+            .collect(),
+    }
+}
+
+async fn list_remotes() -> anyhow::Result<()> {
+    let discovery = Discovery::start()?;
+    tracing::info!("listening for remotes on the local network...");
+    time::sleep(DISCOVERY_TIMEOUT).await;
+
+    let remotes = discovery.remotes();
+    anyhow::ensure!(
+        !remotes.is_empty(),
+        "no remotes were discovered on the local network"
+    );
+
+    for remote in remotes {
+        println!("{remote}");
+    }
+
+    Ok(())
 }
 
 pub fn find_available_socket() -> Option<PathBuf> {
@@ -42,41 +147,73 @@ pub fn find_available_socket() -> Option<PathBuf> {
 }
 
 async fn try_main() -> anyhow::Result<()> {
-    let (span, config) = dip_common::common::<Config>()?;
-    let socket_path = config.socket_path(
-        find_available_socket,
-        "no more sockets available (too many discord clients open?)",
-    )?;
+    let (span, loader, config) = dip_common::common::<Config>()?;
+
+    if loader.arguments().list_remotes {
+        drop(span);
+        return list_remotes().await;
+    }
+
+    let socket_path = config
+        .socket_path(
+            find_available_socket,
+            "no more sockets available (too many discord clients open?)",
+        )?
+        .into_owned();
     tracing::info!("socket path is {}", socket_path.display());
 
-    let remote_address = config
-        .remote_address
-        .with_context(|| {
-            format!(
-                "the remote address must be passed in either the arguments or the config ('{}')",
-                Config::toml().display()
-            )
-        })?
-        .with_port(DEFAULT_PORT);
-    tracing::info!(%remote_address, "remote address to connect to");
+    // remotes are only discovered if there's no remote address to connect to. They keep being
+    // discovered while serving, so a remote which starts later on is still found
+    let discovery = if config.remote_address.is_empty() {
+        tracing::info!("no remote address is set, discovering remotes on the local network");
+        let discovery = Discovery::start()?;
+
+        if discovery.wait(DISCOVERY_TIMEOUT).await.is_empty() {
+            tracing::warn!(
+                "no remotes were discovered on the local network yet, so apps are told the remote \
+                 is unreachable until one is. A remote address can be passed in either the \
+                 arguments or the config ('{}')",
+                loader.file().path.display()
+            );
+        }
+
+        Some(discovery)
+    } else {
+        None
+    };
+
+    let remote_addresses = remote_addresses(&config, discovery.as_ref());
+
+    if !remote_addresses.is_empty() {
+        let listed = remote_addresses
+            .iter()
+            .map(|address| address.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        tracing::info!(
+            remote_addresses = %listed,
+            "remote addresses to connect to, in order of priority"
+        );
+    }
 
     tracing::info!("successfully resolved configuration");
     drop(span);
 
-    let destroy_socket_on_drop = OnceLock::new();
+    let keep_socket = config.keep_socket;
+    let fatal_errors = config.link.fatal_errors.clone();
+    let config = dip_common::reload::watch(loader, config)?;
+    let policy = dip_common::reload::map(config.clone(), |config: &Config| {
+        Ok(SessionPolicy {
+            offline: config.offline,
+            ..SessionPolicy::link_config(&config.link)?
+        })
+    })?;
 
-    if !config.keep_socket {
-        let (destroy_on_drop, fut) = utils::destroy_path_on_termination(socket_path.to_path_buf())?;
+    let shutdown = Shutdown::on_signals()?;
 
-        // we need to do this because we need `destroy_on_drop` to stay alive until the end of the
-        // main function, otherwise it'll get dropped by the end of this scope and then the unix
-        // socket gets deleted immediately, which is bad if we want external programs to interact
-        // with our unix socket, cuz, y'know, it doesn't exist.
-        destroy_socket_on_drop
-            .set(destroy_on_drop)
-            .unwrap_or_else(|_| panic!("`destroy_socket_on_drop` already set"));
-
-        tokio::spawn(fut);
+    // if the socket already exists, binding to it fails, and it isn't ours to remove
+    if !keep_socket && !socket_path.exists() {
+        shutdown.remove_on_exit(socket_path.clone());
     }
 
     #[cfg(unix)]
@@ -85,16 +222,36 @@ async fn try_main() -> anyhow::Result<()> {
     #[cfg(windows)]
     let new_client_name = "named pipe";
 
-    dip_common::serve::<UnixListener, TcpStream, _, _>(
-        socket_path.to_path_buf(),
-        remote_address,
+    let hooks = ServeHooks::default()
+        .on_stream_connect_fail(|_| tracing::warn!("is the remote client currently on right now?"))
+        .stream_preamble(Hello::new(None))
+        .unavailable(close_code::REMOTE_UNREACHABLE, "remote unreachable")
+        .fatal_errors(fatal_errors)
+        .live_policy(move || Arc::clone(&policy.borrow()))
+        .shutdown(shutdown.clone());
+
+    let result = dip_common::serve::<UnixListener, TcpStream, _, _>(
+        socket_path,
+        move || {
+            // the remote addresses may have been unset by a reload when nothing is being
+            // discovered, so fall back to the initial ones. Discovered remotes which went away
+            // are gone for good, though.
+            let addresses = self::remote_addresses(&config.borrow(), discovery.as_ref());
+
+            if addresses.is_empty() && discovery.is_none() {
+                remote_addresses.clone()
+            } else {
+                addresses
+            }
+        },
         new_client_name,
         "remote client",
-        ServeHooks::default().on_stream_connect_fail(|_| {
-            tracing::warn!("is the remote client currently on right now?")
-        }),
+        hooks,
     )
-    .await
+    .await;
+
+    shutdown.remove_files().await;
+    result
 }
 
 #[tokio::main]
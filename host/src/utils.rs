@@ -1,96 +1,62 @@
 use anyhow::Context;
-use fs_err::tokio as fs;
-use serde::de::Error;
+use dip_common::serve::HostAndPort;
+use serde::de::value::SeqAccessDeserializer;
+use serde::de::{Error, IntoDeserializer, SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
-use std::path::{Path, PathBuf};
-use std::process;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
-use tokio::signal::unix;
-use tokio::signal::unix::SignalKind;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::UnboundedReceiver;
-
-fn multiple_signals(
-    signals: impl IntoIterator<Item = SignalKind>,
-) -> anyhow::Result<UnboundedReceiver<()>> {
-    let (sender, receiver) = mpsc::unbounded_channel();
-    let signals = signals
-        .into_iter()
-        .map(unix::signal)
-        .collect::<Result<Vec<_>, _>>()
-        .context("failed to register all signals")?;
-
-    for mut signal in signals {
-        let sender = sender.clone();
-
-        tokio::spawn(async move {
-            loop {
-                if signal.recv().await.is_some() {
-                    sender.send(()).unwrap();
-                } else {
-                    break;
-                }
-            }
-        });
-    }
 
-    Ok(receiver)
+/// A host name or ip address, with an optional port.
+///
+/// Host names are kept as is, and are resolved on every connection. IPv6 addresses may have a zone
+/// id, such as `fe80::1%eth0`, which is resolved along with the address.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MaybeSocketAddr {
+    pub host: String,
+    pub port: Option<u16>,
 }
 
-async fn destroy_path(path: impl AsRef<Path>) {
-    let path = path.as_ref();
-
-    if let Err(error) = fs::remove_file(path).await {
-        tracing::error!(?error, "failed to remove unix socket")
-    } else {
-        tracing::debug!(?path, "removed unix socket")
+impl MaybeSocketAddr {
+    pub fn with_port(&self, port: u16) -> (String, u16) {
+        (self.host.clone(), self.port.unwrap_or(port))
     }
-}
 
-pub struct DestroyPathOnDrop(pub PathBuf);
+    fn parse_host(host: &str) -> anyhow::Result<String> {
+        fn is_valid(part: &str, allowed: &str) -> bool {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || allowed.contains(c))
+        }
 
-impl Drop for DestroyPathOnDrop {
-    fn drop(&mut self) {
-        tokio::spawn(destroy_path(self.0.clone()));
-    }
-}
+        if let Ok(address) = IpAddr::from_str(host) {
+            return Ok(address.to_string());
+        }
 
-pub fn destroy_path_on_termination(
-    path: PathBuf,
-) -> anyhow::Result<(DestroyPathOnDrop, impl Future<Output = ()>)> {
-    let mut signals = multiple_signals([
-        SignalKind::terminate(),
-        SignalKind::interrupt(),
-        SignalKind::quit(),
-    ])
-    .context("failed to register SIGTERM, SIGINT, and SIGQUIT signals")?;
-
-    tracing::debug!("destroy path {} on termination", path.display());
-
-    let destroy_path_on_drop = DestroyPathOnDrop(path.clone());
-
-    Ok((destroy_path_on_drop, async move {
-        signals.recv().await;
-        destroy_path(path).await;
-        process::exit(0)
-    }))
-}
+        if let Some((address, zone)) = host.split_once('%') {
+            Ipv6Addr::from_str(address).context("failed to parse ipv6 address")?;
+            anyhow::ensure!(is_valid(zone, "-_."), "invalid zone id `{zone}`");
 
-#[derive(Clone, Copy)]
-pub struct MaybeSocketAddr {
-    pub address: IpAddr,
-    pub port: Option<u16>,
-}
+            return Ok(host.to_owned());
+        }
 
-impl MaybeSocketAddr {
-    pub fn to_socket_addr(self) -> Option<SocketAddr> {
-        self.port.map(|port| SocketAddr::new(self.address, port))
+        anyhow::ensure!(
+            host.split('.').all(|label| is_valid(label, "-_")),
+            "`{host}` is neither an ip address nor a valid host name"
+        );
+
+        Ok(host.to_owned())
     }
+}
 
-    pub fn with_port(self, port: u16) -> SocketAddr {
-        SocketAddr::new(self.address, self.port.unwrap_or(port))
+impl fmt::Display for MaybeSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => HostAndPort(&self.host, port).fmt(f),
+            None => f.write_str(&self.host),
+        }
     }
 }
 
@@ -99,11 +65,7 @@ impl Serialize for MaybeSocketAddr {
     where
         S: Serializer,
     {
-        if let Some(socket_addr) = self.to_socket_addr() {
-            socket_addr.serialize(serializer)
-        } else {
-            self.address.serialize(serializer)
-        }
+        serializer.collect_str(self)
     }
 }
 
@@ -114,7 +76,7 @@ impl<'de> Deserialize<'de> for MaybeSocketAddr {
     {
         Self::from_str(&String::deserialize(deserializer)?).map_err(|e| {
             D::Error::custom(format!(
-                "socket address or ip address could not be deserialized successfully: {e}"
+                "socket address, ip address or host name could not be deserialized successfully: {e:#}"
             ))
         })
     }
@@ -124,10 +86,40 @@ impl FromStr for MaybeSocketAddr {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        SocketAddr::from_str(s).map(Self::from).or_else(|_| {
-            Ok(Self::from(
-                IpAddr::from_str(s).context("failed to parse ip address")?,
-            ))
+        if let Ok(address) = SocketAddr::from_str(s) {
+            return Ok(Self::from(address));
+        }
+
+        // `[host]:port` or `[host]`, where the host is an ipv6 address which may have a zone id
+        if let Some(rest) = s.strip_prefix('[') {
+            let (host, port) = rest.split_once(']').context("missing closing `]`")?;
+            let port = match port {
+                "" => None,
+                port => Some(
+                    port.strip_prefix(':')
+                        .context("expected `:` after `]`")?
+                        .parse()
+                        .context("failed to parse port")?,
+                ),
+            };
+
+            return Ok(Self {
+                host: Self::parse_host(host)?,
+                port,
+            });
+        }
+
+        // more than one colon means an ipv6 address without a port
+        let (host, port) = match s.split_once(':') {
+            Some((host, port)) if !port.contains(':') => {
+                (host, Some(port.parse().context("failed to parse port")?))
+            }
+            _ => (s, None),
+        };
+
+        Ok(Self {
+            host: Self::parse_host(host)?,
+            port,
         })
     }
 }
@@ -135,7 +127,7 @@ impl FromStr for MaybeSocketAddr {
 impl From<SocketAddr> for MaybeSocketAddr {
     fn from(value: SocketAddr) -> Self {
         Self {
-            address: value.ip(),
+            host: value.ip().to_string(),
             port: Some(value.port()),
         }
     }
@@ -144,8 +136,130 @@ impl From<SocketAddr> for MaybeSocketAddr {
 impl From<IpAddr> for MaybeSocketAddr {
     fn from(value: IpAddr) -> Self {
         Self {
-            address: value,
+            host: value.to_string(),
             port: None,
         }
     }
 }
+
+/// Deserializes either a single value or a list of values into a list, so that a single remote
+/// address doesn't have to be written as a list.
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct OneOrMany<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for OneOrMany<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or a list of strings")
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+            T::deserialize(v.into_deserializer()).map(|value| vec![value])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany(PhantomData))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MaybeSocketAddr;
+
+    fn parse(s: &str) -> (String, Option<u16>) {
+        let address = s
+            .parse::<MaybeSocketAddr>()
+            .unwrap_or_else(|error| panic!("failed to parse `{s}`: {error:#}"));
+
+        (address.host, address.port)
+    }
+
+    fn host(host: &str, port: Option<u16>) -> (String, Option<u16>) {
+        (host.to_owned(), port)
+    }
+
+    #[test]
+    fn ipv4() {
+        assert_eq!(parse("192.168.86.31"), host("192.168.86.31", None));
+        assert_eq!(
+            parse("192.168.86.31:20800"),
+            host("192.168.86.31", Some(20800))
+        );
+    }
+
+    #[test]
+    fn ipv6() {
+        assert_eq!(parse("::1"), host("::1", None));
+        assert_eq!(parse("0:0:0:0:0:0:0:1"), host("::1", None));
+        assert_eq!(parse("fe80::1"), host("fe80::1", None));
+        assert_eq!(parse("[::1]"), host("::1", None));
+        assert_eq!(parse("[::1]:20800"), host("::1", Some(20800)));
+    }
+
+    #[test]
+    fn ipv6_with_zone_id() {
+        assert_eq!(parse("fe80::1%eth0"), host("fe80::1%eth0", None));
+        assert_eq!(parse("fe80::1%wlp3s0.1"), host("fe80::1%wlp3s0.1", None));
+        assert_eq!(parse("[fe80::1%eth0]"), host("fe80::1%eth0", None));
+        assert_eq!(
+            parse("[fe80::1%eth0]:20800"),
+            host("fe80::1%eth0", Some(20800))
+        );
+    }
+
+    #[test]
+    fn host_names() {
+        assert_eq!(parse("desktop"), host("desktop", None));
+        assert_eq!(parse("desktop.lan"), host("desktop.lan", None));
+        assert_eq!(
+            parse("my-pc_2.local:20800"),
+            host("my-pc_2.local", Some(20800))
+        );
+    }
+
+    #[test]
+    fn rejected() {
+        for s in [
+            "[::1",
+            "[::1]x",
+            "[::1]:",
+            "[::1]:notaport",
+            "host:notaport",
+            "host:65536",
+            "bad host",
+            "desktop..lan",
+            ".lan",
+            "desk/top",
+            "fe80::1%",
+            "fe80::1%eth 0",
+            "1.2.3.4%eth0",
+            "",
+        ] {
+            assert!(
+                s.parse::<MaybeSocketAddr>().is_err(),
+                "`{s}` should not have been parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in [
+            "192.168.86.31",
+            "192.168.86.31:20800",
+            "[::1]:20800",
+            "[fe80::1%eth0]:20800",
+            "desktop.lan:20800",
+        ] {
+            assert_eq!(s.parse::<MaybeSocketAddr>().unwrap().to_string(), s);
+        }
+    }
+}
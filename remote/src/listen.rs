@@ -0,0 +1,142 @@
+use anyhow::Context;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// An address to listen for host connections on.
+#[derive(Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    /// An IPv4 or IPv6 address, with an optional port.
+    Ip { address: IpAddr, port: Option<u16> },
+
+    /// Every address of a network interface, such as `wg0`, with an optional port.
+    Interface { name: String, port: Option<u16> },
+}
+
+impl ListenAddress {
+    /// Resolves this into the socket addresses to bind to, using `port` if this has no port.
+    pub fn resolve(&self, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        match self {
+            Self::Ip {
+                address,
+                port: own_port,
+            } => Ok(vec![SocketAddr::new(*address, own_port.unwrap_or(port))]),
+            Self::Interface {
+                name,
+                port: own_port,
+            } => {
+                let interfaces = local_ip_address::list_afinet_netifas()
+                    .context("failed to list network interfaces")?;
+                let addresses = interfaces
+                    .into_iter()
+                    .filter(|(interface, _)| interface == name)
+                    .map(|(_, address)| address)
+                    .filter(|address| {
+                        // link-local ipv6 addresses can't be bound to without a scope id, which
+                        // isn't given to us
+                        let link_local = matches!(
+                            address,
+                            IpAddr::V6(address) if address.segments()[0] & 0xffc0 == 0xfe80
+                        );
+
+                        if link_local {
+                            tracing::debug!("skipping link-local address {address} of {name}");
+                        }
+
+                        !link_local
+                    })
+                    .map(|address| SocketAddr::new(address, own_port.unwrap_or(port)))
+                    .collect::<Vec<_>>();
+
+                anyhow::ensure!(
+                    !addresses.is_empty(),
+                    "network interface `{name}` does not exist or has no usable addresses"
+                );
+
+                Ok(addresses)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip {
+                address,
+                port: Some(port),
+            } => SocketAddr::new(*address, *port).fmt(f),
+            Self::Ip {
+                address,
+                port: None,
+            } => address.fmt(f),
+            Self::Interface {
+                name,
+                port: Some(port),
+            } => write!(f, "{name}:{port}"),
+            Self::Interface { name, port: None } => f.write_str(name),
+        }
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = SocketAddr::from_str(s) {
+            return Ok(Self::Ip {
+                address: address.ip(),
+                port: Some(address.port()),
+            });
+        }
+
+        let unbracketed = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s);
+
+        if let Ok(address) = IpAddr::from_str(unbracketed) {
+            return Ok(Self::Ip {
+                address,
+                port: None,
+            });
+        }
+
+        let (name, port) = match s.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port.parse().context("failed to parse port")?)),
+            None => (s, None),
+        };
+
+        anyhow::ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_graphic() && c != ':'),
+            "`{s}` is neither an ip address nor a network interface name"
+        );
+
+        Ok(Self::Interface {
+            name: name.to_owned(),
+            port,
+        })
+    }
+}
+
+impl Serialize for ListenAddress {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Self::from_str(&String::deserialize(deserializer)?).map_err(|e| {
+            D::Error::custom(format!("listen address could not be deserialized: {e:#}"))
+        })
+    }
+}
@@ -1,31 +1,140 @@
+mod listen;
+
+use crate::listen::ListenAddress;
+use anyhow::Context;
 use clap::Parser;
-use dip_common::config::ConfigLike;
-use dip_common::serve::ServeHooks;
+use dip_common::access::{AccessList, IpNet};
+use dip_common::config::{CommonArgs, ConfigLike};
+use dip_common::frame::close_code;
+use dip_common::link::LinkConfig;
+use dip_common::preamble::Hello;
+use dip_common::serve::{ServeHooks, SessionLimits, SessionPolicy, SocketAddrs, TcpListeners};
+use dip_common::shutdown::Shutdown;
 use dip_common::DEFAULT_PORT;
 use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::process::ExitCode;
-use tokio::net::{TcpListener, UnixStream};
+use std::sync::Arc;
+use tokio::net::UnixStream;
 
-#[derive(Serialize, Deserialize, Parser)]
+#[derive(Serialize, Deserialize, Parser, Default)]
 #[command(author, version, about)]
 pub struct Config {
     /// The port to accept host connections to. If not specified, it will default to 49131.
     #[clap(short = 'P', long)]
     pub port: Option<u16>,
 
+    /// The addresses to accept host connections on. Each is an IPv4 or IPv6 address, or the name
+    /// of a network interface to listen on all addresses of, optionally followed by a port (the
+    /// port above is used otherwise). If not specified, it will listen on all IPv4 addresses.
+    #[clap(short, long)]
+    #[serde(default)]
+    pub listen: Vec<ListenAddress>,
+
+    /// Whether or not to announce this remote on the local network, so that hosts without a remote
+    /// address can discover it. Default value is "true".
+    #[clap(long)]
+    pub announce: Option<bool>,
+
+    /// The name this remote is announced as, and tells hosts which connect to it. If not
+    /// specified, the host name of this computer is used.
+    #[clap(short, long)]
+    pub name: Option<String>,
+
     /// The location of the Discord IPC path. If not specified, it will be automatically detected.
     #[clap(short = 'p', long)]
     pub discord_ipc_path: Option<PathBuf>,
+
+    /// The log filter, in the same syntax as the `RUST_LOG` environment variable. If not
+    /// specified, `RUST_LOG` is used, falling back to `info`.
+    #[clap(long)]
+    pub log: Option<String>,
+
+    /// Whether or not to reload the configuration when the configuration file changes. It is
+    /// always reloaded on SIGHUP.
+    #[clap(long)]
+    #[serde(default)]
+    pub watch_config: bool,
+
+    /// The ranges of host ip addresses which may connect, in CIDR notation such as
+    /// `192.168.1.0/24`. If not specified, every host may connect unless it is denied.
+    #[clap(long)]
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+
+    /// The ranges of host ip addresses which may not connect, even if they are allowed.
+    #[clap(long)]
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+
+    /// How many sessions may be served at once. Connections over the limit are answered with a
+    /// Close frame telling there are too many sessions. If not specified, there is no limit.
+    #[clap(long)]
+    pub max_sessions: Option<usize>,
+
+    /// How many sessions may be served at once for each host ip address. If not specified, there
+    /// is no limit.
+    #[clap(long)]
+    pub max_sessions_per_ip: Option<usize>,
+
+    /// Whether or not to shift the timestamps of activities by how far the clock of the host is
+    /// off from the clock of this computer, so that Discord shows the right elapsed or remaining
+    /// time. Default value is "true".
+    #[clap(long)]
+    pub correct_clock_skew: Option<bool>,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub link: LinkConfig,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    pub common: CommonArgs,
 }
 
 impl<'de> ConfigLike<'de> for Config {
     const FILE_NAME: &'static str = "remote.toml";
+    const SECTION: &'static str = "remote";
+    const RELOADABLE: &'static [&'static str] = &[
+        "log",
+        "watch_config",
+        "allow",
+        "deny",
+        "max_sessions",
+        "max_sessions_per_ip",
+        "correct_clock_skew",
+        "heartbeat_interval",
+        "heartbeat_timeout",
+        "tcp_keepalive",
+        "tcp_nodelay",
+        "linger",
+        "coalesce_activity",
+        "dedup_activity",
+        "script",
+        "script_budget",
+        "rewrite",
+    ];
 
     fn discord_ipc_path(&self) -> &Option<PathBuf> {
         &self.discord_ipc_path
     }
+
+    fn common_args(&self) -> &CommonArgs {
+        &self.common
+    }
+
+    fn log(&self) -> Option<&str> {
+        self.log.as_deref()
+    }
+
+    fn watch_config(&self) -> bool {
+        self.watch_config
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.link.validate()
+    }
 }
 
 pub fn find_existing_socket() -> Option<PathBuf> {
@@ -38,35 +147,114 @@ fn fetch_local_ip(
     ip_type: &str,
 ) {
     match fetch_fn() {
-        Ok(ip_address) => tracing::info!("remote {ip_type} address is {ip_address}:{port}"),
+        Ok(ip_address) => {
+            let address = SocketAddr::new(ip_address, port);
+            tracing::info!("remote {ip_type} address is {address}")
+        }
         Err(error) => tracing::warn!("failed to retrieve local {ip_type} address: {error}"),
     }
 }
 
 async fn try_main() -> anyhow::Result<()> {
-    let (span, config) = dip_common::common::<Config>()?;
-    let socket_path = config.socket_path(
-        find_existing_socket,
-        "no existing sockets are available (is discord open?)",
-    )?;
+    let (span, loader, config) = dip_common::common::<Config>()?;
+    let socket_path = config
+        .socket_path(
+            find_existing_socket,
+            "no existing sockets are available (is discord open?)",
+        )?
+        .into_owned();
     tracing::info!("socket path is {}", socket_path.display());
 
     let port = config.port.unwrap_or(DEFAULT_PORT);
     tracing::info!(?port, "port to listen on");
 
+    let listen = if config.listen.is_empty() {
+        vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)]
+    } else {
+        let mut listen = Vec::new();
+
+        for address in &config.listen {
+            for address in address
+                .resolve(port)
+                .with_context(|| format!("failed to resolve listen address `{address}`"))?
+            {
+                if !listen.contains(&address) {
+                    listen.push(address);
+                }
+            }
+        }
+
+        listen
+    };
+    let listen = SocketAddrs(listen);
+    tracing::info!(%listen, "addresses to listen on");
+
+    if !config.allow.is_empty() || !config.deny.is_empty() {
+        tracing::info!(allow = ?config.allow, deny = ?config.deny, "hosts which may connect");
+    }
+
     tracing::info!("successfully resolved configuration");
     drop(span);
 
-    fetch_local_ip(local_ip_address::local_ip, port, "ipv4");
-    fetch_local_ip(local_ip_address::local_ipv6, port, "ipv6");
+    let name = config
+        .name
+        .clone()
+        .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned());
+    let announce = config.announce.unwrap_or(true);
+    let fatal_errors = config.link.fatal_errors.clone();
+    let config = dip_common::reload::watch(loader, config)?;
+    let policy = dip_common::reload::map(config.clone(), |config: &Config| {
+        Ok(SessionPolicy {
+            correct_clock_skew: config.correct_clock_skew.unwrap_or(true),
+            ..SessionPolicy::link_config(&config.link)?
+        })
+    })?;
+
+    // the actual addresses are only unknown if we listen on an unspecified address
+    for address in &listen.0 {
+        match address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                fetch_local_ip(local_ip_address::local_ip, address.port(), "ipv4")
+            }
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                fetch_local_ip(local_ip_address::local_ipv6, address.port(), "ipv6")
+            }
+            _ => {}
+        }
+    }
+
+    if announce {
+        let port = listen.0.first().map_or(port, SocketAddr::port);
+        tracing::info!("announcing this remote as {name} on the local network");
+        dip_common::discovery::announce(name.clone(), port).await?;
+    }
+
+    let limits = config.clone();
+    let access_list = config;
 
-    dip_common::serve::<TcpListener, UnixStream, _, _>(
-        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
-        socket_path.to_path_buf(),
+    dip_common::serve::<TcpListeners, UnixStream, _, _>(
+        listen,
+        move || vec![socket_path.clone()],
         "host server",
         "discord ipc",
         ServeHooks::default()
-            .on_stream_connect_fail(|_| tracing::warn!("was discord open then closed?")),
+            .on_stream_connect_fail(|_| tracing::warn!("was discord open then closed?"))
+            .new_client_preamble(Hello::new(Some(name)))
+            .fatal_errors(fatal_errors)
+            .live_policy(move || Arc::clone(&policy.borrow()))
+            .max_sessions(move || {
+                let config = limits.borrow();
+                SessionLimits {
+                    total: config.max_sessions,
+                    per_ip: config.max_sessions_per_ip,
+                }
+            })
+            .access_list(move || {
+                let config = access_list.borrow();
+                AccessList::new(config.allow.clone(), config.deny.clone())
+            })
+            .unavailable(close_code::DISCORD_NOT_RUNNING, "Discord not running")
+            .shutdown(Shutdown::on_signals()?),
     )
     .await
 }